@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident, LitInt, LitStr, Path, Variant};
+
+/// Implements `sfo_result::ErrorCode` and `Display` for a fieldless enum.
+///
+/// ```
+/// use sfo_result::ErrorCode;
+///
+/// #[derive(Copy, Clone, Debug, ErrorCode)]
+/// enum Code {
+///     #[code(id = 1, msg = "not found", category = "user")]
+///     NotFound,
+///     #[code(id = 2, name = "busy", retryable, severity = "warning")]
+///     Busy,
+/// }
+///
+/// assert_eq!(Code::Busy.id(), 2);
+/// assert_eq!(Code::Busy.to_string(), "busy");
+/// ```
+///
+/// Crates that depend on sfo-result under another name point the generated
+/// code at it with `#[code(crate = "...")]`:
+///
+/// ```
+/// use sfo_result as renamed;
+///
+/// #[derive(Copy, Clone, Debug, renamed::ErrorCode)]
+/// #[code(crate = "renamed")]
+/// enum Code {
+///     #[code(id = 1)]
+///     Failed,
+/// }
+/// ```
+///
+/// Ids must be unique:
+///
+/// ```compile_fail
+/// #[derive(Copy, Clone, Debug, sfo_result::ErrorCode)]
+/// enum Code {
+///     #[code(id = 1)]
+///     First,
+///     #[code(id = 1)]
+///     Second,
+/// }
+/// ```
+///
+/// Every variant needs an id:
+///
+/// ```compile_fail
+/// #[derive(Copy, Clone, Debug, sfo_result::ErrorCode)]
+/// enum Code {
+///     #[code(msg = "no id")]
+///     Missing,
+/// }
+/// ```
+///
+/// Variants cannot carry fields:
+///
+/// ```compile_fail
+/// #[derive(Copy, Clone, Debug, sfo_result::ErrorCode)]
+/// enum Code {
+///     #[code(id = 1)]
+///     Io(u32),
+/// }
+/// ```
+#[proc_macro_derive(ErrorCode, attributes(code))]
+pub fn derive_error_code(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+struct CodeAttr {
+    ident: Ident,
+    id: u32,
+    name: String,
+    msg: String,
+    retryable: bool,
+    category: Option<TokenStream2>,
+    severity: Option<TokenStream2>,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => return Err(Error::new(input.ident.span(), "ErrorCode can only be derived for enums")),
+    };
+
+    let mut codes = Vec::new();
+    let mut errors: Option<Error> = None;
+    let mut ids: HashMap<u32, Ident> = HashMap::new();
+    for variant in data.variants.iter() {
+        match parse_variant(variant) {
+            Ok((code, id_span)) => {
+                if let Some(used) = ids.get(&code.id) {
+                    let err = Error::new(id_span, format!("duplicate error code id {}, already used by `{}`", code.id, used));
+                    combine(&mut errors, err);
+                } else {
+                    ids.insert(code.id, code.ident.clone());
+                }
+                codes.push(code);
+            }
+            Err(err) => combine(&mut errors, err),
+        }
+    }
+    if let Some(errors) = errors {
+        return Err(errors);
+    }
+
+    let krate = crate_path(&input)?;
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let id_arms = codes.iter().map(|c| {
+        let (v, id) = (&c.ident, c.id);
+        quote!(Self::#v => #id,)
+    });
+    let name_arms = codes.iter().map(|c| {
+        let (v, name) = (&c.ident, &c.name);
+        quote!(Self::#v => #name,)
+    });
+    let msg_arms = codes.iter().map(|c| {
+        let (v, msg) = (&c.ident, &c.msg);
+        quote!(Self::#v => #msg,)
+    });
+    let category_arms = codes.iter().map(|c| {
+        let v = &c.ident;
+        let category = c.category.clone().unwrap_or_else(|| quote!(Internal));
+        quote!(Self::#v => #krate::ErrorCategory::#category,)
+    });
+    let severity_arms = codes.iter().map(|c| {
+        let v = &c.ident;
+        let severity = c.severity.clone().unwrap_or_else(|| quote!(Error));
+        quote!(Self::#v => #krate::Severity::#severity,)
+    });
+    let retryable_arms = codes.iter().map(|c| {
+        let (v, retryable) = (&c.ident, c.retryable);
+        quote!(Self::#v => #retryable || #krate::ErrorCode::category(self) == #krate::ErrorCategory::Transient,)
+    });
+
+    Ok(quote! {
+        impl #impl_generics #krate::ErrorCode for #ident #ty_generics #where_clause {
+            fn id(&self) -> u32 {
+                match self {
+                    #(#id_arms)*
+                }
+            }
+
+            fn name(&self) -> &'static str {
+                match self {
+                    #(#name_arms)*
+                }
+            }
+
+            fn message(&self) -> &'static str {
+                match self {
+                    #(#msg_arms)*
+                }
+            }
+
+            fn category(&self) -> #krate::ErrorCategory {
+                match self {
+                    #(#category_arms)*
+                }
+            }
+
+            fn severity(&self) -> #krate::Severity {
+                match self {
+                    #(#severity_arms)*
+                }
+            }
+
+            fn is_retryable(&self) -> bool {
+                match self {
+                    #(#retryable_arms)*
+                }
+            }
+        }
+
+        impl #impl_generics ::std::fmt::Display for #ident #ty_generics #where_clause {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                f.write_str(#krate::ErrorCode::name(self))
+            }
+        }
+    })
+}
+
+// `::sfo_result` unless overridden with `#[code(crate = "...")]` on the enum,
+// for crates that depend on sfo-result under another name.
+fn crate_path(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let mut krate = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("code")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("crate") {
+                let lit: LitStr = meta.value()?.parse()?;
+                krate = Some(lit.parse::<Path>()?);
+                Ok(())
+            } else {
+                Err(meta.error("unknown code attribute, expected `crate`"))
+            }
+        })?;
+    }
+    Ok(match krate {
+        Some(krate) => quote!(#krate),
+        None => quote!(::sfo_result),
+    })
+}
+
+fn parse_variant(variant: &Variant) -> syn::Result<(CodeAttr, proc_macro2::Span)> {
+    if !matches!(variant.fields, Fields::Unit) {
+        return Err(Error::new(variant.span(), "error code variants must not have fields"));
+    }
+
+    let mut code = CodeAttr {
+        ident: variant.ident.clone(),
+        id: 0,
+        name: variant.ident.to_string(),
+        msg: String::new(),
+        retryable: false,
+        category: None,
+        severity: None,
+    };
+    let mut id_span = None;
+    for attr in variant.attrs.iter().filter(|a| a.path().is_ident("code")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                let lit: LitInt = meta.value()?.parse()?;
+                code.id = lit.base10_parse()?;
+                id_span = Some(lit.span());
+            } else if meta.path.is_ident("name") {
+                let lit: LitStr = meta.value()?.parse()?;
+                code.name = lit.value();
+            } else if meta.path.is_ident("msg") {
+                let lit: LitStr = meta.value()?.parse()?;
+                code.msg = lit.value();
+            } else if meta.path.is_ident("retryable") {
+                code.retryable = true;
+            } else if meta.path.is_ident("category") {
+                let lit: LitStr = meta.value()?.parse()?;
+                code.category = Some(match lit.value().as_str() {
+                    "user" => quote!(User),
+                    "transient" => quote!(Transient),
+                    "internal" => quote!(Internal),
+                    _ => return Err(Error::new(lit.span(), "expected one of \"user\", \"transient\", \"internal\"")),
+                });
+            } else if meta.path.is_ident("severity") {
+                let lit: LitStr = meta.value()?.parse()?;
+                code.severity = Some(match lit.value().as_str() {
+                    "info" => quote!(Info),
+                    "warning" => quote!(Warning),
+                    "error" => quote!(Error),
+                    "critical" => quote!(Critical),
+                    _ => return Err(Error::new(lit.span(), "expected one of \"info\", \"warning\", \"error\", \"critical\"")),
+                });
+            } else {
+                return Err(meta.error("unknown code attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    match id_span {
+        Some(span) => Ok((code, span)),
+        None => Err(Error::new(variant.span(), "missing `#[code(id = ...)]` on error code variant")),
+    }
+}
+
+fn combine(errors: &mut Option<Error>, err: Error) {
+    match errors {
+        Some(errors) => errors.combine(err),
+        None => *errors = Some(err),
+    }
+}
@@ -0,0 +1,302 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::causes::{Causes, ChainLimits};
+use crate::erased::erase;
+
+pub type CodeMatcher = Arc<dyn Fn(&dyn Any) -> bool + Send + Sync>;
+
+/// Decides which errors capture a backtrace when they are built. The default
+/// is `Always` with the `backtrace` feature and `Never` without it.
+#[derive(Clone)]
+pub enum BacktracePolicy {
+    Never,
+    /// `Backtrace::force_capture()` for every error.
+    Always,
+    /// `Backtrace::capture()`, enabled by `RUST_BACKTRACE` / `RUST_LIB_BACKTRACE`.
+    Env,
+    /// Force capture for one in every N errors, 0 disables capture.
+    Sample(u64),
+    /// Force capture for errors whose code matches the predicate.
+    Codes(CodeMatcher),
+}
+
+impl BacktracePolicy {
+    pub fn code<C: PartialEq + Send + Sync + 'static>(code: C) -> Self {
+        Self::codes([code])
+    }
+
+    pub fn codes<C: PartialEq + Send + Sync + 'static>(codes: impl IntoIterator<Item = C>) -> Self {
+        let codes: Vec<C> = codes.into_iter().collect();
+        Self::Codes(Arc::new(move |code| {
+            code.downcast_ref::<C>().is_some_and(|code| codes.contains(code))
+        }))
+    }
+
+    // `is_multiple_of` would need Rust 1.87.
+    #[allow(clippy::manual_is_multiple_of)]
+    pub(crate) fn capture(&self, code: &dyn Any) -> Option<Backtrace> {
+        match self {
+            BacktracePolicy::Never => None,
+            BacktracePolicy::Always => Some(Backtrace::force_capture()),
+            BacktracePolicy::Env => Some(Backtrace::capture()),
+            BacktracePolicy::Sample(rate) => {
+                if *rate != 0 && SAMPLE_COUNTER.fetch_add(1, Ordering::Relaxed) % *rate == 0 {
+                    Some(Backtrace::force_capture())
+                } else {
+                    None
+                }
+            }
+            BacktracePolicy::Codes(matches) => {
+                if matches(code) {
+                    Some(Backtrace::force_capture())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl Debug for BacktracePolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BacktracePolicy::Never => write!(f, "Never"),
+            BacktracePolicy::Always => write!(f, "Always"),
+            BacktracePolicy::Env => write!(f, "Env"),
+            BacktracePolicy::Sample(rate) => write!(f, "Sample({})", rate),
+            BacktracePolicy::Codes(_) => write!(f, "Codes(..)"),
+        }
+    }
+}
+
+#[cfg(feature = "backtrace")]
+const DEFAULT_POLICY: BacktracePolicy = BacktracePolicy::Always;
+#[cfg(not(feature = "backtrace"))]
+const DEFAULT_POLICY: BacktracePolicy = BacktracePolicy::Never;
+
+static POLICY: RwLock<BacktracePolicy> = RwLock::new(DEFAULT_POLICY);
+static SAMPLE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_backtrace_policy(policy: BacktracePolicy) {
+    *POLICY.write().unwrap_or_else(|e| e.into_inner()) = policy;
+}
+
+pub fn backtrace_policy() -> BacktracePolicy {
+    POLICY.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+pub(crate) fn capture(code: &dyn Any) -> Option<Backtrace> {
+    POLICY.read().unwrap_or_else(|e| e.into_inner()).capture(code)
+}
+
+// Whether the chain starting at `source` already has a backtrace. Every sfo
+// error records that for its own chain when built, so the walk stops at the
+// first one.
+pub(crate) fn below(source: Option<&(dyn std::error::Error + 'static)>) -> bool {
+    let Some(source) = source else {
+        return false;
+    };
+    match erase(source) {
+        Some(erased) => erased.chain_has_backtrace(),
+        None => Causes::new(source, ChainLimits::unlimited()).find_map(erase).is_some_and(|e| e.chain_has_backtrace()),
+    }
+}
+
+/// How captured backtraces are rendered by the `Debug` / `Display` impls. The
+/// default prints them verbatim.
+#[derive(Clone, Debug, Default)]
+pub struct BacktraceFormat {
+    hide_std: bool,
+    crates: Vec<String>,
+    location_only: bool,
+}
+
+impl BacktraceFormat {
+    pub const fn full() -> Self {
+        Self {
+            hide_std: false,
+            crates: Vec::new(),
+            location_only: false,
+        }
+    }
+
+    /// Drops std frames and prints only file:line.
+    pub const fn compact() -> Self {
+        Self {
+            hide_std: true,
+            crates: Vec::new(),
+            location_only: true,
+        }
+    }
+
+    /// Drop std/core/alloc frames and the runtime frames around `main`.
+    pub fn hide_std(mut self, hide: bool) -> Self {
+        self.hide_std = hide;
+        self
+    }
+
+    /// Collapse runs of frames whose symbol does not start with one of these
+    /// paths, e.g. "my_app" or "my_lib::net", into a single line.
+    pub fn crates<S: Into<String>>(mut self, crates: impl IntoIterator<Item = S>) -> Self {
+        self.crates = crates.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Print `file:line` instead of the symbol and its location.
+    pub fn location_only(mut self, location_only: bool) -> Self {
+        self.location_only = location_only;
+        self
+    }
+
+    fn is_full(&self) -> bool {
+        !self.hide_std && self.crates.is_empty() && !self.location_only
+    }
+
+    pub(crate) fn render(&self, backtrace: &str) -> String {
+        if self.is_full() {
+            return backtrace.to_string();
+        }
+
+        let mut out = String::new();
+        let mut omitted = 0;
+        for line in parse(backtrace) {
+            let frame = match line {
+                Line::Frame(frame) => frame,
+                Line::Other(line) => {
+                    flush_omitted(&mut out, &mut omitted);
+                    out.push_str(line);
+                    out.push('\n');
+                    continue;
+                }
+            };
+            if self.hide_std && is_std(frame.symbol) {
+                continue;
+            }
+            if !self.crates.is_empty() && !self.crates.iter().any(|c| in_crate(frame.symbol, c)) {
+                omitted += 1;
+                continue;
+            }
+            flush_omitted(&mut out, &mut omitted);
+            match frame.index {
+                Some(index) => out.push_str(&format!("{:>4}: ", index)),
+                None => out.push_str("      "),
+            }
+            match frame.location {
+                Some(location) if self.location_only => out.push_str(strip_column(location)),
+                _ => out.push_str(frame.symbol),
+            }
+            out.push('\n');
+            if let (Some(location), false) = (frame.location, self.location_only) {
+                out.push_str(&format!("             at {}\n", location));
+            }
+        }
+        flush_omitted(&mut out, &mut omitted);
+        out
+    }
+}
+
+// One symbol of the `Display` output of `std::backtrace::Backtrace`. Inlined
+// symbols share the frame index of the symbol above them.
+pub(crate) struct Frame<'a> {
+    pub index: Option<&'a str>,
+    pub symbol: &'a str,
+    pub location: Option<&'a str>,
+}
+
+pub(crate) enum Line<'a> {
+    Frame(Frame<'a>),
+    Other(&'a str),
+}
+
+pub(crate) fn parse(backtrace: &str) -> Vec<Line<'_>> {
+    let mut lines: Vec<Line> = Vec::new();
+    for line in backtrace.lines() {
+        let trimmed = line.trim();
+        if let Some(location) = trimmed.strip_prefix("at ") {
+            if let Some(Line::Frame(frame)) = lines.last_mut() {
+                if frame.location.is_none() {
+                    frame.location = Some(location);
+                    continue;
+                }
+            }
+        }
+        let indexed = trimmed.split_once(": ").filter(|(index, _)| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()));
+        if let Some((index, symbol)) = indexed {
+            lines.push(Line::Frame(Frame {
+                index: Some(index),
+                symbol,
+                location: None,
+            }));
+        } else if !trimmed.is_empty() && matches!(lines.last(), Some(Line::Frame(_))) {
+            lines.push(Line::Frame(Frame {
+                index: None,
+                symbol: trimmed,
+                location: None,
+            }));
+        } else {
+            lines.push(Line::Other(line));
+        }
+    }
+    lines
+}
+
+// `<&dyn core::ops::Fn<..> as ..>::call` -> `core::ops::Fn<..> as ..>::call`
+fn symbol_path(symbol: &str) -> &str {
+    let mut symbol = symbol;
+    loop {
+        let trimmed = symbol.trim_start_matches(['<', '&']).trim_start_matches("dyn ").trim_start_matches("mut ");
+        if trimmed.len() == symbol.len() {
+            return symbol;
+        }
+        symbol = trimmed;
+    }
+}
+
+// `app` matches `app::read` but not `apple::read`.
+fn in_crate(symbol: &str, krate: &str) -> bool {
+    match symbol_path(symbol).strip_prefix(krate) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+fn is_std(symbol: &str) -> bool {
+    const RUNTIME: &[&str] = &["main", "<unknown>", "_start", "__libc_start_main", "__libc_start_call_main", "start_thread", "clone", "clone3", "BaseThreadInitThunk", "RtlUserThreadStart"];
+    let path = symbol_path(symbol);
+    let krate = path.split("::").next().unwrap_or(path);
+    matches!(krate, "std" | "core" | "alloc") || krate.starts_with("__rust") || RUNTIME.contains(&symbol)
+}
+
+fn strip_column(location: &str) -> &str {
+    match location.rsplit_once(':') {
+        Some((rest, column)) if column.bytes().all(|b| b.is_ascii_digit()) && rest.rsplit_once(':').is_some() => rest,
+        _ => location,
+    }
+}
+
+fn flush_omitted(out: &mut String, omitted: &mut usize) {
+    match *omitted {
+        0 => {}
+        1 => out.push_str("      ... 1 frame omitted\n"),
+        n => out.push_str(&format!("      ... {} frames omitted\n", n)),
+    }
+    *omitted = 0;
+}
+
+static FORMAT: RwLock<BacktraceFormat> = RwLock::new(BacktraceFormat::full());
+
+pub fn set_backtrace_format(format: BacktraceFormat) {
+    *FORMAT.write().unwrap_or_else(|e| e.into_inner()) = format;
+}
+
+pub fn backtrace_format() -> BacktraceFormat {
+    FORMAT.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+pub(crate) fn render(backtrace: &str) -> String {
+    FORMAT.read().unwrap_or_else(|e| e.into_inner()).render(backtrace)
+}
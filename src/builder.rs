@@ -0,0 +1,69 @@
+use std::any::type_name;
+use std::borrow::Cow;
+use std::fmt::Debug;
+
+use crate::{Attachment, BacktracePolicy, Error};
+
+/// Step by step construction of an `Error`, for when some of the pieces are
+/// optional. Created by `Error::builder`.
+pub struct ErrorBuilder<T> {
+    code: T,
+    msg: Cow<'static, str>,
+    source: Option<Box<dyn std::error::Error + 'static + Send + Sync>>,
+    source_type: Option<&'static str>,
+    attachments: Vec<Attachment>,
+    backtrace: Option<BacktracePolicy>,
+}
+
+impl<T: Debug + Copy + Sync + Send + 'static> ErrorBuilder<T> {
+    pub(crate) fn new(code: T) -> Self {
+        Self {
+            code,
+            msg: Cow::Borrowed(""),
+            source: None,
+            source_type: None,
+            attachments: Vec::new(),
+            backtrace: None,
+        }
+    }
+
+    pub fn msg(mut self, msg: impl Into<Cow<'static, str>>) -> Self {
+        self.msg = msg.into();
+        self
+    }
+
+    pub fn source<E: std::error::Error + 'static + Send + Sync>(mut self, source: E) -> Self {
+        self.source = Some(Box::new(source));
+        self.source_type = Some(type_name::<E>());
+        self
+    }
+
+    pub fn attach<A: Debug + Send + Sync + 'static>(mut self, value: A) -> Self {
+        self.attachments.push(Attachment {
+            key: None,
+            value: Box::new(value),
+        });
+        self
+    }
+
+    pub fn attach_kv<A: Debug + Send + Sync + 'static>(mut self, key: &'static str, value: A) -> Self {
+        self.attachments.push(Attachment {
+            key: Some(key),
+            value: Box::new(value),
+        });
+        self
+    }
+
+    /// Overrides the global backtrace policy for this error only.
+    pub fn backtrace(mut self, policy: BacktracePolicy) -> Self {
+        self.backtrace = Some(policy);
+        self
+    }
+
+    #[track_caller]
+    pub fn build(self) -> Error<T> {
+        let mut error = Error::build(self.code, self.msg, self.source, self.source_type, self.backtrace.as_ref());
+        error.inner.attachments = self.attachments;
+        error
+    }
+}
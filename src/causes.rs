@@ -0,0 +1,180 @@
+use std::fmt::{Display, Formatter};
+use std::sync::RwLock;
+
+/// Bounds applied when a report renders the source chain: at most `max_depth`
+/// causes are shown and every message is cut after `max_msg_len` characters.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChainLimits {
+    max_depth: usize,
+    max_msg_len: usize,
+}
+
+impl ChainLimits {
+    pub const fn new() -> Self {
+        Self {
+            max_depth: 32,
+            max_msg_len: 4096,
+        }
+    }
+
+    pub const fn unlimited() -> Self {
+        Self {
+            max_depth: usize::MAX,
+            max_msg_len: usize::MAX,
+        }
+    }
+
+    pub const fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub const fn max_msg_len(mut self, max_msg_len: usize) -> Self {
+        self.max_msg_len = max_msg_len;
+        self
+    }
+
+    pub fn depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn msg_len(&self) -> usize {
+        self.max_msg_len
+    }
+
+    /// `msg` cut to `max_msg_len` characters, ending with "..." when cut.
+    pub fn truncate<'a>(&self, msg: &'a str) -> Truncated<'a> {
+        Truncated {
+            msg,
+            max_len: self.max_msg_len,
+        }
+    }
+}
+
+impl Default for ChainLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Truncated<'a> {
+    msg: &'a str,
+    max_len: usize,
+}
+
+impl Display for Truncated<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.msg.char_indices().nth(self.max_len) {
+            Some((end, _)) => write!(f, "{}...", &self.msg[..end]),
+            None => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+/// Iterates the sources below an error, stopping after `max_depth` causes or
+/// when a cause comes back around. `rest` then tells what was left out.
+pub struct Causes<'a> {
+    next: Option<&'a (dyn std::error::Error + 'static)>,
+    seen: Vec<&'a (dyn std::error::Error + 'static)>,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl<'a> Causes<'a> {
+    pub fn new(error: &'a (dyn std::error::Error + 'static), limits: ChainLimits) -> Self {
+        Self {
+            next: error.source(),
+            seen: vec![error],
+            depth: 0,
+            max_depth: limits.max_depth,
+        }
+    }
+
+    /// Counts the causes that were not yielded. Iterating is not required
+    /// first, any causes still within the depth limit are counted too.
+    pub fn rest(mut self) -> Rest {
+        let mut omitted = 0;
+        while let Some(e) = self.next {
+            if seen(&self.seen, e) {
+                return Rest {
+                    omitted,
+                    cycle: true,
+                };
+            }
+            self.seen.push(e);
+            omitted += 1;
+            self.next = e.source();
+        }
+        Rest {
+            omitted,
+            cycle: false,
+        }
+    }
+}
+
+impl<'a> Iterator for Causes<'a> {
+    type Item = &'a (dyn std::error::Error + 'static);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let e = self.next?;
+        if self.depth >= self.max_depth || seen(&self.seen, e) {
+            return None;
+        }
+        self.seen.push(e);
+        self.depth += 1;
+        self.next = e.source();
+        Some(e)
+    }
+}
+
+// Compares the vtable as well as the address: a wrapper whose first field is
+// the error it wraps shares that error's address. The same type coerced to
+// `dyn Error` in two places may get two vtables, a cycle through it is then
+// found one lap later.
+#[allow(ambiguous_wide_pointer_comparisons)]
+fn seen(seen: &[&(dyn std::error::Error + 'static)], e: &(dyn std::error::Error + 'static)) -> bool {
+    seen.iter().any(|s| std::ptr::eq(*s, e))
+}
+
+/// What `Causes` left out. Displays as the marker closing a truncated chain,
+/// and as nothing when the whole chain was shown.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rest {
+    omitted: usize,
+    cycle: bool,
+}
+
+impl Rest {
+    pub fn omitted(&self) -> usize {
+        self.omitted
+    }
+
+    pub fn is_cycle(&self) -> bool {
+        self.cycle
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.omitted == 0 && !self.cycle
+    }
+}
+
+impl Display for Rest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.omitted, self.cycle) {
+            (0, false) => Ok(()),
+            (0, true) => write!(f, "... (cycle detected)"),
+            (1, _) => write!(f, "... (1 more cause)"),
+            (n, _) => write!(f, "... ({} more causes)", n),
+        }
+    }
+}
+
+static LIMITS: RwLock<ChainLimits> = RwLock::new(ChainLimits::new());
+
+pub fn set_chain_limits(limits: ChainLimits) {
+    *LIMITS.write().unwrap_or_else(|e| e.into_inner()) = limits;
+}
+
+pub fn chain_limits() -> ChainLimits {
+    *LIMITS.read().unwrap_or_else(|e| e.into_inner())
+}
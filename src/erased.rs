@@ -0,0 +1,76 @@
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::RwLock;
+
+use crate::{Attachment, ErrorLocation};
+
+// `Error<T>` is generic over its code, so a `&dyn std::error::Error` found in a
+// source chain cannot be downcast to it without naming `T`. Every code type
+// registers a downcast function the first time an error with that code is
+// built, which lets the chain be inspected across code types.
+pub(crate) trait ErasedError {
+    fn code_type(&self) -> &'static str;
+    fn fmt_code_value(&self, f: &mut Formatter<'_>) -> std::fmt::Result;
+    fn msg(&self) -> &str;
+    fn attachments(&self) -> &[Attachment];
+    fn as_error(&self) -> &(dyn std::error::Error + 'static);
+    fn location(&self) -> Option<&ErrorLocation>;
+    fn chain_has_backtrace(&self) -> bool;
+    fn backtrace_string(&self) -> Option<String>;
+    #[cfg(feature = "serde")]
+    fn type_name(&self) -> &'static str;
+    #[cfg(feature = "serde")]
+    fn source_type(&self) -> Option<&'static str>;
+
+    fn fmt_code(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.code_type())?;
+        self.fmt_code_value(f)
+    }
+}
+
+type Downcast = for<'a> fn(&'a (dyn std::error::Error + 'static)) -> Option<&'a dyn ErasedError>;
+
+static REGISTRY: RwLock<Vec<(TypeId, Downcast)>> = RwLock::new(Vec::new());
+
+thread_local! {
+    static REGISTERED: RefCell<Vec<TypeId>> = const { RefCell::new(Vec::new()) };
+}
+
+fn downcast<'a, T: Debug + Copy + Send + Sync + 'static>(e: &'a (dyn std::error::Error + 'static)) -> Option<&'a dyn ErasedError> {
+    crate::as_error::<T>(e).map(|e| e as &dyn ErasedError)
+}
+
+pub(crate) fn register<T: Debug + Copy + Send + Sync + 'static>() {
+    let type_id = TypeId::of::<T>();
+    let known = REGISTERED.try_with(|registered| {
+        let mut registered = registered.borrow_mut();
+        if registered.contains(&type_id) {
+            true
+        } else {
+            registered.push(type_id);
+            false
+        }
+    });
+    if known == Ok(true) {
+        return;
+    }
+
+    let mut registry = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+    if !registry.iter().any(|(id, _)| *id == type_id) {
+        registry.push((type_id, downcast::<T>));
+    }
+}
+
+pub(crate) fn erase<'a>(e: &'a (dyn std::error::Error + 'static)) -> Option<&'a dyn ErasedError> {
+    let registry = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+    registry.iter().find_map(|(_, downcast)| downcast(e))
+}
+
+pub(crate) struct Code<'a>(pub &'a dyn ErasedError);
+
+impl Display for Code<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt_code(f)
+    }
+}
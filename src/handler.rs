@@ -0,0 +1,318 @@
+use std::fmt::{Debug, Display, Formatter, Write};
+use std::sync::{Arc, RwLock};
+
+use crate::causes::{chain_limits, Causes, ChainLimits};
+use crate::erased::{erase, Code, ErasedError};
+use crate::fmt_backtrace;
+
+/// Renders the `{:?}` report of an error. Installed globally with
+/// `set_report_handler`, `PlainHandler` is used until then.
+pub trait ReportHandler: Send + Sync {
+    fn report(&self, error: ErrorView<'_>, f: &mut Formatter<'_>) -> std::fmt::Result;
+}
+
+/// Code-type independent view of an `Error`, handed to report handlers.
+#[derive(Clone, Copy)]
+pub struct ErrorView<'a> {
+    error: &'a dyn ErasedError,
+}
+
+impl<'a> ErrorView<'a> {
+    pub(crate) fn new(error: &'a dyn ErasedError) -> Self {
+        Self {
+            error,
+        }
+    }
+
+    /// `None` when `error` is not an sfo `Error` (or `SharedError`).
+    pub fn of(error: &'a (dyn std::error::Error + 'static)) -> Option<Self> {
+        erase(error).map(Self::new)
+    }
+
+    pub fn code_type(&self) -> &'static str {
+        self.error.code_type()
+    }
+
+    /// `Debug` of the code, without its type.
+    pub fn code(&self) -> String {
+        let mut code = String::new();
+        let _ = write!(code, "{}", CodeValue(self.error));
+        code
+    }
+
+    pub fn msg(&self) -> &'a str {
+        self.error.msg()
+    }
+
+    pub fn location(&self) -> Option<String> {
+        self.error.location().map(|l| l.to_string())
+    }
+
+    pub fn attachments(&self) -> impl Iterator<Item = (Option<&'static str>, &'a dyn Debug)> + 'a {
+        self.error.attachments().iter().map(|a| (a.key, a.value.as_ref() as _))
+    }
+
+    pub fn backtrace(&self) -> Option<String> {
+        self.error.backtrace_string()
+    }
+
+    pub fn source(&self) -> Option<&'a (dyn std::error::Error + 'static)> {
+        self.error.as_error().source()
+    }
+
+    pub fn error(&self) -> &'a (dyn std::error::Error + 'static) {
+        self.error.as_error()
+    }
+
+    /// The sources of this error within the global `ChainLimits`.
+    pub fn causes(&self) -> Causes<'a> {
+        Causes::new(self.error(), chain_limits())
+    }
+}
+
+struct CodeValue<'a>(&'a dyn ErasedError);
+
+impl Display for CodeValue<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt_code_value(f)
+    }
+}
+
+/// The multi-line report: the error with its location and attachments, the
+/// `Display` of every source on a numbered "Caused by:" line, and the
+/// innermost backtrace.
+pub struct PlainHandler;
+
+impl ReportHandler for PlainHandler {
+    fn report(&self, error: ErrorView<'_>, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fmt_tree(error, f, false, chain_limits())
+    }
+}
+
+/// `PlainHandler` with ANSI colors, for terminals.
+pub struct AnsiHandler;
+
+impl ReportHandler for AnsiHandler {
+    fn report(&self, error: ErrorView<'_>, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fmt_tree(error, f, true, chain_limits())
+    }
+}
+
+const BOLD_RED: &str = "\x1b[1;31m";
+const BOLD_YELLOW: &str = "\x1b[1;33m";
+const CYAN: &str = "\x1b[36m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+pub(crate) fn fmt_tree(error: ErrorView<'_>, f: &mut Formatter<'_>, colored: bool, limits: ChainLimits) -> std::fmt::Result {
+    let color = |color: &'static str| if colored { color } else { "" };
+    let fmt_layer = |error: ErrorView<'_>, f: &mut Formatter<'_>| -> std::fmt::Result {
+        write!(f, "{}{}{}", color(BOLD_RED), Code(error.error), color(RESET))?;
+        if !error.msg().is_empty() {
+            write!(f, ", msg:{}", limits.truncate(error.msg()))?;
+        }
+        if let Some(location) = error.location() {
+            write!(f, "\n    {}at {}{}", color(DIM), location, color(RESET))?;
+        }
+        for (key, value) in error.attachments() {
+            write!(f, "\n{}Attachment:{} ", color(CYAN), color(RESET))?;
+            match key {
+                Some(key) => write!(f, "{}={}", key, limits.truncate(&format!("{:?}", value)))?,
+                None => write!(f, "{}", limits.truncate(&format!("{:?}", value)))?,
+            }
+        }
+        Ok(())
+    };
+
+    fmt_layer(error, f)?;
+    let mut backtrace = error.backtrace();
+    let mut causes = Causes::new(error.error(), limits);
+    let rendered: Vec<_> = causes.by_ref().collect();
+    let rest = causes.rest();
+    if !rendered.is_empty() || !rest.is_empty() {
+        write!(f, "\n\n{}Caused by:{}", color(BOLD_YELLOW), color(RESET))?;
+    }
+    for (i, source) in rendered.iter().enumerate() {
+        let msg = limits.truncate(&source.to_string()).to_string();
+        // Like anyhow, a single cause is not numbered.
+        let indent = if rendered.len() == 1 && rest.is_empty() {
+            write!(f, "\n    ")?;
+            4
+        } else {
+            write!(f, "\n{:>5}: ", i)?;
+            i.to_string().len().max(5) + 2
+        };
+        for (j, line) in msg.lines().enumerate() {
+            if j != 0 {
+                write!(f, "\n{:indent$}", "", indent = indent)?;
+            }
+            write!(f, "{}", line)?;
+        }
+        if let Some(inner) = ErrorView::of(*source).and_then(|e| e.backtrace()) {
+            backtrace = Some(inner);
+        }
+    }
+    if !rest.is_empty() {
+        write!(f, "\n    {}", rest)?;
+    }
+    if let Some(backtrace) = backtrace {
+        write!(f, "{}", color(DIM))?;
+        fmt_backtrace(f, backtrace)?;
+        write!(f, "{}", color(RESET))?;
+    }
+    Ok(())
+}
+
+/// Every layer on one line, separated by "; caused by: ", without the
+/// backtrace. For log aggregators that split records on newlines.
+pub struct CompactHandler;
+
+impl ReportHandler for CompactHandler {
+    fn report(&self, error: ErrorView<'_>, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let limits = chain_limits();
+        let fmt_layer = |error: ErrorView<'_>, f: &mut Formatter<'_>| -> std::fmt::Result {
+            write!(f, "{}", Code(error.error))?;
+            if !error.msg().is_empty() {
+                write!(f, ", msg:{}", OneLine(&limits.truncate(error.msg()).to_string()))?;
+            }
+            if let Some(location) = error.location() {
+                write!(f, " at {}", location)?;
+            }
+            let mut attachments = error.attachments().peekable();
+            if attachments.peek().is_some() {
+                write!(f, " [")?;
+                for (i, (key, value)) in attachments.enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    let value = limits.truncate(&format!("{:?}", value)).to_string();
+                    match key {
+                        Some(key) => write!(f, "{}={}", key, OneLine(&value))?,
+                        None => write!(f, "{}", OneLine(&value))?,
+                    }
+                }
+                write!(f, "]")?;
+            }
+            Ok(())
+        };
+
+        fmt_layer(error, f)?;
+        let mut causes = error.causes();
+        for source in causes.by_ref() {
+            write!(f, "; caused by: ")?;
+            match ErrorView::of(source) {
+                Some(source) => fmt_layer(source, f)?,
+                None => write!(f, "{}", OneLine(&limits.truncate(&source.to_string()).to_string()))?,
+            }
+        }
+        let rest = causes.rest();
+        if !rest.is_empty() {
+            write!(f, "; {}", rest)?;
+        }
+        Ok(())
+    }
+}
+
+struct OneLine<'a>(&'a str);
+
+impl Display for OneLine<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, line) in self.0.lines().enumerate() {
+            if i != 0 {
+                write!(f, "\\n")?;
+            }
+            write!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// `key=value` pairs: code_type, code, msg, location, the attachments (keyed
+/// ones under their own key) and the `Display` of the causes joined by ": ".
+pub struct LogfmtHandler;
+
+impl ReportHandler for LogfmtHandler {
+    fn report(&self, error: ErrorView<'_>, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let limits = chain_limits();
+        let msg = limits.truncate(error.msg()).to_string();
+        write!(f, "code_type={} code={} msg={}", Logfmt(error.code_type()), Logfmt(&error.code()), Logfmt(&msg))?;
+        if let Some(location) = error.location() {
+            write!(f, " location={}", Logfmt(&location))?;
+        }
+        for (key, value) in error.attachments() {
+            let value = limits.truncate(&format!("{:?}", value)).to_string();
+            write!(f, " {}={}", key.unwrap_or("attachment"), Logfmt(&value))?;
+        }
+        let mut causes = error.causes();
+        let mut cause = String::new();
+        for source in causes.by_ref() {
+            if !cause.is_empty() {
+                cause.push_str(": ");
+            }
+            let _ = write!(cause, "{}", limits.truncate(&source.to_string()));
+        }
+        let rest = causes.rest();
+        if !rest.is_empty() {
+            let _ = write!(cause, ": {}", rest);
+        }
+        if !cause.is_empty() {
+            write!(f, " cause={}", Logfmt(&cause))?;
+        }
+        Ok(())
+    }
+}
+
+struct Logfmt<'a>(&'a str);
+
+impl Display for Logfmt<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let quote = self.0.is_empty() || self.0.chars().any(|c| c == ' ' || c == '=' || c == '"' || c.is_control());
+        if !quote {
+            return write!(f, "{}", self.0);
+        }
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+/// One JSON object in the shape of `Error::to_json_report`: "code" with its
+/// type and value, "message", "location", "attachments", a "causes" array with
+/// one object per source, "truncated" and the frames of the innermost
+/// "backtrace".
+#[cfg(feature = "json")]
+pub struct JsonHandler;
+
+#[cfg(feature = "json")]
+impl ReportHandler for JsonHandler {
+    fn report(&self, error: ErrorView<'_>, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", serde_json::Value::Object(crate::json::report(error.error)))
+    }
+}
+
+static HANDLER: RwLock<Option<Arc<dyn ReportHandler>>> = RwLock::new(None);
+
+pub fn set_report_handler(handler: impl ReportHandler + 'static) {
+    *HANDLER.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(handler));
+}
+
+pub fn report_handler() -> Arc<dyn ReportHandler> {
+    HANDLER.read().unwrap_or_else(|e| e.into_inner()).clone().unwrap_or_else(|| Arc::new(PlainHandler))
+}
+
+// The lock is released before rendering, so a handler that formats other
+// errors does not take it recursively.
+pub(crate) fn report(error: &dyn ErasedError, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let handler = HANDLER.read().unwrap_or_else(|e| e.into_inner()).clone();
+    match handler {
+        Some(handler) => handler.report(ErrorView::new(error), f),
+        None => PlainHandler.report(ErrorView::new(error), f),
+    }
+}
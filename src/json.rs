@@ -0,0 +1,111 @@
+use serde_json::{json, Map, Value};
+
+use crate::backtrace::{parse, Line};
+use crate::causes::{chain_limits, Causes, ChainLimits};
+use crate::erased::{erase, ErasedError};
+use crate::{Error, ErrorCode, ErrorView};
+
+impl<T: ErrorCode + Sync + Send + 'static> Error<T> {
+    /// Structured form of the `Debug` report for log pipelines that ingest
+    /// JSON, the `JsonHandler` output with the name and id of the code added.
+    pub fn to_json_report(&self) -> Value {
+        let mut report = report(self);
+        if let Some(Value::Object(code)) = report.get_mut("code") {
+            code.insert("name".to_string(), json!(self.inner.code.name()));
+            code.insert("id".to_string(), json!(self.inner.code.id()));
+        }
+        Value::Object(report)
+    }
+}
+
+// The backtrace is the innermost one in the chain, split into frames. Causes
+// are bounded by `chain_limits`, "truncated" tells what was left out.
+pub(crate) fn report(error: &dyn ErasedError) -> Map<String, Value> {
+    let limits = chain_limits();
+    let mut report = Map::new();
+    report.insert("code".to_string(), code(error));
+    insert_details(&mut report, error, limits);
+
+    let mut causes = Vec::new();
+    let mut backtrace = error.backtrace_string();
+    let mut type_name = error.source_type();
+    let mut chain = Causes::new(error.as_error(), limits);
+    for e in chain.by_ref() {
+        let mut cause = Map::new();
+        match erase(e) {
+            Some(erased) => {
+                cause.insert("type".to_string(), json!(erased.type_name()));
+                cause.insert("code".to_string(), code(erased));
+                insert_details(&mut cause, erased, limits);
+                backtrace = erased.backtrace_string().or(backtrace);
+                type_name = erased.source_type();
+            }
+            None => {
+                cause.insert("type".to_string(), json!(type_name.take()));
+                cause.insert("message".to_string(), json!(limits.truncate(&e.to_string()).to_string()));
+            }
+        }
+        causes.push(Value::Object(cause));
+    }
+    report.insert("causes".to_string(), Value::Array(causes));
+    let rest = chain.rest();
+    report.insert("truncated".to_string(), match rest.is_empty() {
+        true => Value::Null,
+        false => json!({
+            "omitted": rest.omitted(),
+            "cycle": rest.is_cycle(),
+        }),
+    });
+    report.insert("backtrace".to_string(), match backtrace {
+        Some(backtrace) => frames(&backtrace),
+        None => Value::Null,
+    });
+    report
+}
+
+fn code(error: &dyn ErasedError) -> Value {
+    json!({
+        "type": error.code_type(),
+        "value": ErrorView::new(error).code(),
+    })
+}
+
+fn insert_details(object: &mut Map<String, Value>, error: &dyn ErasedError, limits: ChainLimits) {
+    object.insert("message".to_string(), json!(limits.truncate(error.msg()).to_string()));
+    object.insert("location".to_string(), json!(error.location().map(|l| l.to_string())));
+    let attachments = error.attachments().iter().map(|a| json!({
+        "key": a.key,
+        "value": limits.truncate(&format!("{:?}", a.value)).to_string(),
+    }));
+    object.insert("attachments".to_string(), Value::Array(attachments.collect()));
+}
+
+pub(crate) fn frames(backtrace: &str) -> Value {
+    let frames = parse(backtrace).into_iter().filter_map(|line| match line {
+        Line::Frame(frame) => Some(frame),
+        Line::Other(_) => None,
+    }).map(|frame| {
+        let (file, line, column) = match frame.location {
+            Some(location) => split_location(location),
+            None => (None, None, None),
+        };
+        json!({
+            "index": frame.index.and_then(|i| i.parse::<u32>().ok()),
+            "symbol": frame.symbol,
+            "file": file,
+            "line": line,
+            "column": column,
+        })
+    });
+    Value::Array(frames.collect())
+}
+
+// `path:line:column`, the path may itself contain ':' on Windows.
+fn split_location(location: &str) -> (Option<&str>, Option<u32>, Option<u32>) {
+    let mut parts = location.rsplitn(3, ':');
+    let (column, line, file) = (parts.next(), parts.next(), parts.next());
+    match (file, line.and_then(|l| l.parse().ok()), column.and_then(|c| c.parse().ok())) {
+        (Some(file), Some(line), Some(column)) => (Some(file), Some(line), Some(column)),
+        _ => (Some(location), None, None),
+    }
+}
@@ -1,180 +1,633 @@
-use std::any::{type_name};
+use std::any::{type_name, Any};
 use std::backtrace::{Backtrace, BacktraceStatus};
+use std::borrow::Cow;
 use std::fmt::{Debug, Display};
+use std::panic::Location;
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "derive")]
+pub use sfo_result_derive::ErrorCode;
+
+// Lets code generated by sfo-result-derive refer to `::sfo_result` inside this crate.
+extern crate self as sfo_result;
+
+mod backtrace;
+mod builder;
+mod causes;
+mod erased;
+mod handler;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "serde")]
+mod remote;
+mod report;
+mod shared;
+
+pub use backtrace::{backtrace_format, backtrace_policy, set_backtrace_format, set_backtrace_policy, BacktraceFormat, BacktracePolicy, CodeMatcher};
+pub use builder::ErrorBuilder;
+pub use causes::{chain_limits, set_chain_limits, Causes, ChainLimits, Rest, Truncated};
+pub use handler::{report_handler, set_report_handler, AnsiHandler, CompactHandler, ErrorView, LogfmtHandler, PlainHandler, ReportHandler};
+#[cfg(feature = "json")]
+pub use handler::JsonHandler;
 #[cfg(feature = "serde")]
-#[derive(Serialize, Deserialize)]
+pub use remote::RemoteError;
+pub use report::{Layer, Report};
+pub use shared::SharedError;
+
+/// Boxed so that `Result<_, Error<T>>` stays one pointer wide on the error
+/// side, whatever the code type.
 pub struct Error<T> {
-    code: T,
-    msg: String,
-    #[serde(skip)]
-    source: Option<Box<(dyn std::error::Error + 'static + Send + Sync)>>,
-    #[serde(skip)]
-    backtrace: Option<Backtrace>,
+    inner: Box<ErrorImpl<T>>,
 }
 
-#[cfg(not(feature = "serde"))]
-pub struct Error<T> {
+struct ErrorImpl<T> {
     code: T,
-    msg: String,
-    source: Option<Box<(dyn std::error::Error + 'static + Send + Sync)>>,
-    backtrace: Option<Backtrace>,
+    msg: Cow<'static, str>,
+    source: Option<Box<dyn std::error::Error + 'static + Send + Sync>>,
+    source_type: Option<&'static str>,
+    backtrace: Option<ErrorBacktrace>,
+    // Whether this error or one of its causes has a backtrace, so wrapping
+    // errors only need to look at the nearest sfo error below them.
+    chain_backtrace: bool,
+    location: Option<ErrorLocation>,
+    attachments: Vec<Attachment>,
+}
+
+struct Attachment {
+    key: Option<&'static str>,
+    value: Box<dyn AttachmentValue>,
+}
+
+trait AttachmentValue: Any + Debug + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<A: Any + Debug + Send + Sync> AttachmentValue for A {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+enum ErrorBacktrace {
+    Local(Backtrace),
+    // Rendered backtrace of an error deserialized from another process.
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    Remote(String),
+}
+
+enum ErrorLocation {
+    Local(&'static Location<'static>),
+    // `file:line:column` of an error deserialized from another process.
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    Remote(String),
+}
+
+impl Display for ErrorLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorLocation::Local(location) => write!(f, "{}", location),
+            ErrorLocation::Remote(location) => write!(f, "{}", location),
+        }
+    }
 }
 
 pub type Result<T, C> = std::result::Result<T, Error<C>>;
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ErrorCategory {
+    /// Caused by invalid input or state on the caller's side.
+    User,
+    /// Temporary failure, the operation may succeed when retried.
+    Transient,
+    /// Bug or unexpected failure inside the system.
+    Internal,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+    Critical,
+}
+
+/// Stable identity of a code: `id` and `name` must not change when the enum is
+/// refactored, so they can be used for serialization, logging and API mapping.
+pub trait ErrorCode: Debug + Copy {
+    fn id(&self) -> u32;
+    fn name(&self) -> &'static str;
+
+    fn category(&self) -> ErrorCategory {
+        ErrorCategory::Internal
+    }
+
+    fn severity(&self) -> Severity {
+        Severity::Error
+    }
+
+    /// Default message used by `Error::from_code`.
+    fn message(&self) -> &'static str {
+        ""
+    }
+
+    fn is_retryable(&self) -> bool {
+        self.category() == ErrorCategory::Transient
+    }
+}
+
 impl<T: Debug + Copy + Sync + Send + 'static> Error<T> {
-    pub fn new(code: T, msg: String) -> Self {
-        #[cfg(feature = "backtrace")]
-        let backtrace = Some(Backtrace::force_capture());
+    #[track_caller]
+    pub fn new(code: T, msg: impl Into<Cow<'static, str>>) -> Self {
+        Self::build(code, msg.into(), None, None, None)
+    }
 
-        #[cfg(not(feature = "backtrace"))]
-        let backtrace = None;
+    #[track_caller]
+    fn build(code: T, msg: Cow<'static, str>, source: Option<Box<dyn std::error::Error + 'static + Send + Sync>>, source_type: Option<&'static str>, policy: Option<&BacktracePolicy>) -> Self {
+        // The innermost backtrace already covers the path to this error, so a
+        // wrapping error only captures one when nothing below it did.
+        let below = backtrace::below(source.as_deref().map(|e| e as _));
+        let backtrace = if below {
+            None
+        } else {
+            match policy {
+                Some(policy) => policy.capture(&code),
+                None => backtrace::capture(&code),
+            }.map(ErrorBacktrace::Local)
+        };
 
-        Self {
-            code,
-            msg,
-            source: None,
-            backtrace,
-        }
+        erased::register::<T>();
+
+        let mut error = Self {
+            inner: Box::new(ErrorImpl {
+                code,
+                msg,
+                source,
+                source_type,
+                backtrace,
+                chain_backtrace: below,
+                location: Some(ErrorLocation::Local(Location::caller())),
+                attachments: Vec::new(),
+            }),
+        };
+        error.inner.chain_backtrace |= error.has_backtrace();
+        error
+    }
+
+    #[track_caller]
+    fn from_source<E: std::error::Error + 'static + Send + Sync>(code: T, msg: Cow<'static, str>, source: E) -> Self {
+        Self::build(code, msg, Some(Box::new(source)), Some(type_name::<E>()), None)
     }
 
     pub fn code(&self) -> T {
-        self.code
+        self.inner.code
     }
 
     pub fn msg(&self) -> &str {
-        &self.msg
+        &self.inner.msg
+    }
+
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        match self.inner.location.as_ref()? {
+            ErrorLocation::Local(location) => Some(location),
+            ErrorLocation::Remote(_) => None,
+        }
     }
 
-    #[cfg(feature = "backtrace")]
     pub fn backtrace(&self) -> Option<&Backtrace> {
-        self.backtrace.as_ref()
+        match self.inner.backtrace.as_ref()? {
+            ErrorBacktrace::Local(backtrace) => Some(backtrace),
+            ErrorBacktrace::Remote(_) => None,
+        }
+    }
+
+    pub fn builder(code: T) -> ErrorBuilder<T> {
+        ErrorBuilder::new(code)
+    }
+
+    /// Replaces the source of this error.
+    pub fn with_source<E: std::error::Error + 'static + Send + Sync>(mut self, source: E) -> Self {
+        self.inner.source = Some(Box::new(source));
+        self.inner.source_type = Some(type_name::<E>());
+        self.inner.chain_backtrace = self.has_backtrace() || backtrace::below(std::error::Error::source(&self));
+        self
+    }
+
+    pub fn set_msg(&mut self, msg: impl Into<Cow<'static, str>>) {
+        self.inner.msg = msg.into();
+    }
+
+    pub fn attach<A: Debug + Send + Sync + 'static>(mut self, value: A) -> Self {
+        self.inner.attachments.push(Attachment {
+            key: None,
+            value: Box::new(value),
+        });
+        self
+    }
+
+    pub fn attach_kv<A: Debug + Send + Sync + 'static>(mut self, key: &'static str, value: A) -> Self {
+        self.inner.attachments.push(Attachment {
+            key: Some(key),
+            value: Box::new(value),
+        });
+        self
+    }
+
+    pub fn get_attachment<A: 'static>(&self) -> Option<&A> {
+        self.inner.attachments.iter().find_map(|a| a.value.as_ref().as_any().downcast_ref::<A>())
+    }
+
+    pub fn get_kv<A: 'static>(&self, key: &str) -> Option<&A> {
+        self.inner.attachments.iter()
+            .filter(|a| a.key == Some(key))
+            .find_map(|a| a.value.as_ref().as_any().downcast_ref::<A>())
     }
+
+    pub fn attachments(&self) -> impl Iterator<Item = (Option<&'static str>, &(dyn Debug + Send + Sync))> {
+        self.inner.attachments.iter().map(|a| (a.key, a.value.as_ref() as _))
+    }
+
+    pub fn report(&self) -> Report {
+        Report::new(self)
+    }
+
+    pub fn chain(&self) -> Chain<'_> {
+        Chain {
+            first: Some(self),
+            causes: Causes::new(self, ChainLimits::unlimited()),
+        }
+    }
+
+    pub fn root_cause(&self) -> &(dyn std::error::Error + 'static) {
+        self.chain().last().unwrap()
+    }
+
+    pub fn downcast_source_ref<E: std::error::Error + 'static>(&self) -> Option<&E> {
+        self.inner.source.as_ref()?.downcast_ref::<E>()
+    }
+
+    pub fn find_source<E: std::error::Error + 'static>(&self) -> Option<&E> {
+        self.chain().skip(1).find_map(|e| e.downcast_ref::<E>())
+    }
+
+    pub fn find_code<C: Debug + Copy + 'static>(&self) -> Option<C> {
+        self.chain().find_map(as_error::<C>).map(|e| e.inner.code)
+    }
+
+    pub fn has_code<C: Debug + Copy + PartialEq + 'static>(&self, code: C) -> bool {
+        self.chain().filter_map(as_error::<C>).any(|e| e.inner.code == code)
+    }
+}
+
+// A `SharedError` in a chain stands in for the error it wraps.
+fn as_error<'a, C: Debug + Copy + 'static>(e: &'a (dyn std::error::Error + 'static)) -> Option<&'a Error<C>> {
+    e.downcast_ref::<Error<C>>().or_else(|| e.downcast_ref::<SharedError<C>>().map(SharedError::error))
 }
 
-impl<T: Debug + Clone + Copy> std::error::Error for Error<T> {
+impl<T: ErrorCode + Sync + Send + 'static> Error<T> {
+    #[track_caller]
+    pub fn from_code(code: T) -> Self {
+        Self::new(code, code.message())
+    }
+
+    pub fn code_id(&self) -> u32 {
+        self.inner.code.id()
+    }
+
+    pub fn code_name(&self) -> &'static str {
+        self.inner.code.name()
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        self.inner.code.category()
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.inner.code.severity()
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.inner.code.is_retryable()
+    }
+}
+
+impl<T> Error<T> {
+    pub fn map_code<U: Debug + Copy + Sync + Send + 'static, F: FnOnce(T) -> U>(self, f: F) -> Error<U> {
+        erased::register::<U>();
+
+        let inner = *self.inner;
+        Error {
+            inner: Box::new(ErrorImpl {
+                code: f(inner.code),
+                msg: inner.msg,
+                source: inner.source,
+                source_type: inner.source_type,
+                backtrace: inner.backtrace,
+                chain_backtrace: inner.chain_backtrace,
+                location: inner.location,
+                attachments: inner.attachments,
+            }),
+        }
+    }
+
+    pub fn into_code<U: CodeFrom<T> + Debug + Copy + Sync + Send + 'static>(self) -> Error<U> {
+        self.map_code(U::code_from)
+    }
+}
+
+/// Converts a code of another module into this code type. A blanket
+/// `From<Error<T>> for Error<U>` would conflict with core's `From<T> for T`,
+/// so `?` goes through `CodeResultExt::into_code` instead.
+pub trait CodeFrom<T> {
+    fn code_from(code: T) -> Self;
+}
+
+/// Iterates over an error and its sources, starting with the error itself.
+/// Stops before a source that comes back around, but not at any depth, so
+/// `root_cause` is the real root of a long chain.
+pub struct Chain<'a> {
+    first: Option<&'a (dyn std::error::Error + 'static)>,
+    causes: Causes<'a>,
+}
+
+impl<'a> Iterator for Chain<'a> {
+    type Item = &'a (dyn std::error::Error + 'static);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.first.take().or_else(|| self.causes.next())
+    }
+}
+
+impl<T: Debug + Copy + 'static> std::error::Error for Error<T> {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        self.source.as_ref().map(|e| e.as_ref() as _)
+        self.inner.source.as_ref().map(|e| e.as_ref() as _)
+    }
+}
+
+impl<T: Debug> Error<T> {
+    fn has_backtrace(&self) -> bool {
+        match &self.inner.backtrace {
+            Some(ErrorBacktrace::Local(backtrace)) => backtrace.status() == BacktraceStatus::Captured,
+            Some(ErrorBacktrace::Remote(_)) => true,
+            None => false,
+        }
+    }
+
+    fn backtrace_string(&self) -> Option<String> {
+        match self.inner.backtrace.as_ref()? {
+            ErrorBacktrace::Local(backtrace) => match backtrace.status() {
+                BacktraceStatus::Captured => Some(backtrace.to_string()),
+                _ => None,
+            },
+            ErrorBacktrace::Remote(backtrace) => Some(backtrace.clone()),
+        }
+    }
+}
+
+fn fmt_backtrace(f: &mut std::fmt::Formatter<'_>, backtrace: String) -> std::fmt::Result {
+    let mut backtrace = backtrace::render(&backtrace);
+    writeln!(f)?;
+    if backtrace.starts_with("stack backtrace:") {
+        // Capitalize to match "Caused by:"
+        backtrace.replace_range(0..1, "S");
+    } else {
+        // "stack backtrace:" prefix was removed in
+        // https://github.com/rust-lang/backtrace-rs/pull/286
+        writeln!(f, "Stack backtrace:")?;
+    }
+    backtrace.truncate(backtrace.trim_end().len());
+    write!(f, "{}", backtrace)
+}
+
+impl<T: Debug + Copy + 'static> erased::ErasedError for Error<T> {
+    fn code_type(&self) -> &'static str {
+        type_name::<T>()
+    }
+
+    fn fmt_code_value(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.inner.code)
+    }
+
+    fn msg(&self) -> &str {
+        &self.inner.msg
+    }
+
+    fn attachments(&self) -> &[Attachment] {
+        &self.inner.attachments
+    }
+
+    fn as_error(&self) -> &(dyn std::error::Error + 'static) {
+        self
+    }
+
+    fn location(&self) -> Option<&ErrorLocation> {
+        self.inner.location.as_ref()
+    }
+
+    fn chain_has_backtrace(&self) -> bool {
+        self.inner.chain_backtrace
+    }
+
+    fn backtrace_string(&self) -> Option<String> {
+        Error::backtrace_string(self)
+    }
+
+    #[cfg(feature = "serde")]
+    fn type_name(&self) -> &'static str {
+        type_name::<Self>()
+    }
+
+    #[cfg(feature = "serde")]
+    fn source_type(&self) -> Option<&'static str> {
+        self.inner.source_type
     }
 }
 
-impl<T: Debug> Debug for Error<T> {
+/// `{:?}` is the report of the installed `ReportHandler` and `{:#?}` a plain
+/// struct dump.
+impl<T: Debug + Copy + 'static> Debug for Error<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{:?}", type_name::<T>(), self.code)?;
-        if !self.msg.is_empty() {
-            write!(f, ", msg:{}", self.msg)?;
-        }
-        if self.source.is_some() {
-            write!(f, "\nCaused by: {:?}", self.source.as_ref().unwrap())?;
-        }
-        if let Some(backtrace) = &self.backtrace {
-            if let BacktraceStatus::Captured = backtrace.status() {
-                let mut backtrace = backtrace.to_string();
-                write!(f, "\n")?;
-                if backtrace.starts_with("stack backtrace:") {
-                    // Capitalize to match "Caused by:"
-                    backtrace.replace_range(0..1, "S");
-                } else {
-                    // "stack backtrace:" prefix was removed in
-                    // https://github.com/rust-lang/backtrace-rs/pull/286
-                    writeln!(f, "Stack backtrace:")?;
-                }
-                backtrace.truncate(backtrace.trim_end().len());
-                write!(f, "{}", backtrace)?;
-            }
+        if !f.alternate() {
+            return handler::report(self, f);
         }
-        Ok(())
+        f.debug_struct("Error")
+            .field("code", &self.inner.code)
+            .field("msg", &self.inner.msg)
+            .field("location", &self.inner.location.as_ref().map(|l| l.to_string()))
+            .field("attachments", &self.inner.attachments.iter().map(|a| (a.key, &a.value)).collect::<Vec<_>>())
+            .field("source", &self.inner.source)
+            .finish()
     }
 }
 
-impl<T: Debug> Display for Error<T> {
+/// `{}` is the one line `code: msg` and `{:#}` appends the `Display` of every
+/// cause, as in `code: msg: cause: root cause`.
+impl<T: Debug + Copy + 'static> Display for Error<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{:?}", type_name::<T>(), self.code)?;
-        if !self.msg.is_empty() {
-            write!(f, ", msg:{}", self.msg)?;
-        }
-        if self.source.is_some() {
-            write!(f, "\nCaused by: {:?}", self.source.as_ref().unwrap())?;
-        }
-        if let Some(backtrace) = &self.backtrace {
-            if let BacktraceStatus::Captured = backtrace.status() {
-                let mut backtrace = backtrace.to_string();
-                write!(f, "\n")?;
-                if backtrace.starts_with("stack backtrace:") {
-                    // Capitalize to match "Caused by:"
-                    backtrace.replace_range(0..1, "S");
-                } else {
-                    // "stack backtrace:" prefix was removed in
-                    // https://github.com/rust-lang/backtrace-rs/pull/286
-                    writeln!(f, "Stack backtrace:")?;
-                }
-                backtrace.truncate(backtrace.trim_end().len());
-                write!(f, "{}", backtrace)?;
+        write!(f, "{:?}", self.inner.code)?;
+        if !self.inner.msg.is_empty() {
+            write!(f, ": {}", self.inner.msg)?;
+        }
+        if f.alternate() {
+            let limits = chain_limits();
+            let mut causes = Causes::new(self, limits);
+            for e in causes.by_ref() {
+                write!(f, ": {}", limits.truncate(&e.to_string()))?;
+            }
+            let rest = causes.rest();
+            if !rest.is_empty() {
+                write!(f, ": {}", rest)?;
             }
         }
         Ok(())
     }
 }
 
-impl<T: Default> From<String> for Error<T> {
+impl<T: Default + Debug + Copy + Sync + Send + 'static> From<String> for Error<T> {
+    #[track_caller]
     fn from(value: String) -> Self {
-        #[cfg(feature = "backtrace")]
-            let backtrace = Some(Backtrace::force_capture());
-
-        #[cfg(not(feature = "backtrace"))]
-            let backtrace = None;
-        Self {
-            code: Default::default(),
-            msg: value,
-            source: None,
-            backtrace,
-        }
+        Self::build(Default::default(), value.into(), None, None, None)
     }
 }
 
-impl<T, E: std::error::Error + 'static + Send + Sync> From<(T, String, E)> for Error<T> {
+impl<T: Debug + Copy + Sync + Send + 'static, E: std::error::Error + 'static + Send + Sync> From<(T, String, E)> for Error<T> {
+    #[track_caller]
     fn from(value: (T, String, E)) -> Self {
-        #[cfg(feature = "backtrace")]
-            let backtrace = Some(Backtrace::force_capture());
+        Self::from_source(value.0, value.1.into(), value.2)
+    }
+}
 
-        #[cfg(not(feature = "backtrace"))]
-            let backtrace = None;
-        Self {
-            code: value.0,
-            msg: value.1,
-            source: Some(Box::new(value.2)),
-            backtrace,
+impl<T: Debug + Copy + Sync + Send + 'static, E: std::error::Error + 'static + Send + Sync> From<(T, &str, E)> for Error<T> {
+    #[track_caller]
+    fn from(value: (T, &str, E)) -> Self {
+        Self::from_source(value.0, value.1.to_string().into(), value.2)
+    }
+}
+
+pub trait ResultExt<V> {
+    fn context<T: Debug + Copy + Sync + Send + 'static>(self, code: T, msg: impl Into<Cow<'static, str>>) -> Result<V, T>;
+    fn with_context<T: Debug + Copy + Sync + Send + 'static, S: Into<Cow<'static, str>>, F: FnOnce() -> S>(self, code: T, f: F) -> Result<V, T>;
+    fn code<T: Debug + Copy + Sync + Send + 'static>(self, code: T) -> Result<V, T>;
+}
+
+impl<V, E: std::error::Error + 'static + Send + Sync> ResultExt<V> for std::result::Result<V, E> {
+    // Closures passed to `map_err` would hide the caller from `#[track_caller]`,
+    // so these match on the result directly.
+    #[track_caller]
+    fn context<T: Debug + Copy + Sync + Send + 'static>(self, code: T, msg: impl Into<Cow<'static, str>>) -> Result<V, T> {
+        match self {
+            Ok(v) => Ok(v),
+            Err(e) => Err(Error::from_source(code, msg.into(), e)),
+        }
+    }
+
+    #[track_caller]
+    fn with_context<T: Debug + Copy + Sync + Send + 'static, S: Into<Cow<'static, str>>, F: FnOnce() -> S>(self, code: T, f: F) -> Result<V, T> {
+        match self {
+            Ok(v) => Ok(v),
+            Err(e) => Err(Error::from_source(code, f().into(), e)),
+        }
+    }
+
+    #[track_caller]
+    fn code<T: Debug + Copy + Sync + Send + 'static>(self, code: T) -> Result<V, T> {
+        match self {
+            Ok(v) => Ok(v),
+            Err(e) => Err(Error::from_source(code, Cow::Borrowed(""), e)),
         }
     }
 }
 
-impl<T, E: std::error::Error + 'static + Send + Sync> From<(T, &str, E)> for Error<T> {
-    fn from(value: (T, &str, E)) -> Self {
-        #[cfg(feature = "backtrace")]
-            let backtrace = Some(Backtrace::force_capture());
+pub trait CodeResultExt<V, T> {
+    fn map_code<U: Debug + Copy + Sync + Send + 'static, F: FnOnce(T) -> U>(self, f: F) -> Result<V, U>;
+    fn into_code<U: CodeFrom<T> + Debug + Copy + Sync + Send + 'static>(self) -> Result<V, U>;
+}
 
-        #[cfg(not(feature = "backtrace"))]
-            let backtrace = None;
-        Self {
-            code: value.0,
-            msg: value.1.to_string(),
-            source: Some(Box::new(value.2)),
-            backtrace,
+impl<V, T> CodeResultExt<V, T> for Result<V, T> {
+    fn map_code<U: Debug + Copy + Sync + Send + 'static, F: FnOnce(T) -> U>(self, f: F) -> Result<V, U> {
+        self.map_err(|e| e.map_code(f))
+    }
+
+    fn into_code<U: CodeFrom<T> + Debug + Copy + Sync + Send + 'static>(self) -> Result<V, U> {
+        self.map_err(Error::into_code)
+    }
+}
+
+pub trait OptionExt<V> {
+    fn ok_or_code<T: Debug + Copy + Sync + Send + 'static>(self, code: T, msg: impl Into<Cow<'static, str>>) -> Result<V, T>;
+    fn ok_or_else_code<T: Debug + Copy + Sync + Send + 'static, S: Into<Cow<'static, str>>, F: FnOnce() -> S>(self, code: T, f: F) -> Result<V, T>;
+}
+
+impl<V> OptionExt<V> for Option<V> {
+    #[track_caller]
+    fn ok_or_code<T: Debug + Copy + Sync + Send + 'static>(self, code: T, msg: impl Into<Cow<'static, str>>) -> Result<V, T> {
+        match self {
+            Some(v) => Ok(v),
+            None => Err(Error::new(code, msg)),
+        }
+    }
+
+    #[track_caller]
+    fn ok_or_else_code<T: Debug + Copy + Sync + Send + 'static, S: Into<Cow<'static, str>>, F: FnOnce() -> S>(self, code: T, f: F) -> Result<V, T> {
+        match self {
+            Some(v) => Ok(v),
+            None => Err(Error::new(code, f())),
         }
     }
 }
 
+#[doc(hidden)]
+#[track_caller]
+pub fn __err<T: Debug + Copy + Sync + Send + 'static>(code: T, args: std::fmt::Arguments) -> Error<T> {
+    let msg = format_msg(args);
+    #[cfg(feature = "log")]
+    log::error!("{}", msg);
+    Error::new(code, msg)
+}
+
+#[doc(hidden)]
+#[track_caller]
+pub fn __into_err<T: Debug + Copy + Sync + Send + 'static, E: std::error::Error + 'static + Send + Sync>(code: T, args: std::fmt::Arguments, e: E) -> Error<T> {
+    let msg = format_msg(args);
+    #[cfg(feature = "log")]
+    if msg.is_empty() {
+        log::error!("err:{:?}", e);
+    } else {
+        log::error!("{} err:{:?}", msg, e);
+    }
+    Error::from_source(code, msg, e)
+}
+
+// Literal-only messages borrow the literal instead of allocating.
+fn format_msg(args: std::fmt::Arguments) -> Cow<'static, str> {
+    match args.as_str() {
+        Some(msg) => Cow::Borrowed(msg),
+        None => Cow::Owned(args.to_string()),
+    }
+}
+
 #[macro_export]
 macro_rules! err {
     ( $err: expr, $($arg:tt)*) => {
-        {
-            #[cfg(feature = "log")]
-            log::error!("{}", format!($($arg)*));
-            sfo_result::Error::new($err, format!("{}", format!($($arg)*)))
+        $crate::__err($err, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! bail {
+    ( $err: expr, $($arg:tt)*) => {
+        return Err($crate::err!($err, $($arg)*).into())
+    };
+}
+
+#[macro_export]
+macro_rules! ensure {
+    ( $cond: expr, $err: expr, $($arg:tt)*) => {
+        if !$cond {
+            $crate::bail!($err, $($arg)*);
         }
     };
 }
@@ -182,24 +635,17 @@ macro_rules! err {
 #[macro_export]
 macro_rules! into_err {
     ($err: expr) => {
-        |e| {
-            #[cfg(feature = "log")]
-            log::error!("err:{:?}", e);
-            sfo_result::Error::from(($err, "".to_string(), e))
-        }
+        |e| $crate::__into_err($err, format_args!(""), e)
     };
     ($err: expr, $($arg:tt)*) => {
-        |e| {
-            #[cfg(feature = "log")]
-            log::error!("{} err:{:?}", format!($($arg)*), e);
-            sfo_result::Error::from(($err, format!($($arg)*), e))
-        }
+        |e| $crate::__into_err($err, format_args!($($arg)*), e)
     };
 }
 
 #[cfg(test)]
 mod test {
     #[derive(Copy, Clone, Debug, Eq, PartialEq, Default)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum TestCode {
         #[default]
         Test1,
@@ -209,8 +655,7 @@ mod test {
 
     #[test]
     fn test() {
-        use crate as sfo_result;
-        let error = sfo_result::Error::new(1, "test".to_string());
+        let error = super::Error::new(1, "test".to_string());
         println!("{:?}", error);
 
         let error = err!(1, "test");
@@ -222,4 +667,1021 @@ mod test {
         // assert_eq!(format!("{:?}", error), "Error: 1, msg: test");
         // assert_eq!(format!("{}", error), "Error: 1, msg: test");
     }
+
+    #[test]
+    fn test_static_msg() {
+        use std::borrow::Cow;
+
+        let error = err!(TestCode::Test1, "static");
+        assert!(matches!(error.inner.msg, Cow::Borrowed("static")));
+        let error = Error::new(TestCode::Test1, "static");
+        assert!(matches!(error.inner.msg, Cow::Borrowed("static")));
+
+        let n = 1;
+        let error = err!(TestCode::Test1, "formatted {}", n);
+        assert!(matches!(error.inner.msg, Cow::Owned(_)));
+        assert_eq!(error.msg(), "formatted 1");
+        let error = Error::new(TestCode::Test1, format!("owned {}", n));
+        assert_eq!(error.msg(), "owned 1");
+    }
+
+    #[test]
+    fn test_shared() {
+        use super::{ResultExt, SharedError};
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let error = ret.context(LowerCode::Timeout, "lower").unwrap_err();
+        let shared = SharedError::new(error);
+        let waiters: Vec<SharedError<LowerCode>> = (0..3).map(|_| shared.clone()).collect();
+        assert!(waiters.iter().all(|e| e.ptr_eq(&shared)));
+        assert_eq!(waiters[0].code(), LowerCode::Timeout);
+        assert_eq!(waiters[0].msg(), "lower");
+        assert_eq!(format!("{:?}", waiters[0]), format!("{:?}", shared.error()));
+        assert!(std::error::Error::source(&waiters[0]).unwrap().is::<Leaf>());
+
+        let ret: std::result::Result<(), SharedError<LowerCode>> = Err(waiters[1].clone());
+        let error = ret.context(TestCode::Test2, "upper").unwrap_err();
+        assert_eq!(error.find_code::<LowerCode>(), Some(LowerCode::Timeout));
+        assert!(error.has_code(LowerCode::Timeout));
+        assert_eq!(error.report().layers()[1].msg(), "lower");
+        assert_eq!(error.chain().count(), 3);
+
+        drop(waiters);
+        drop(error);
+        assert_eq!(shared.try_unwrap().unwrap().code(), LowerCode::Timeout);
+    }
+
+    #[test]
+    fn test_builder() {
+        use super::BacktracePolicy;
+
+        let error = Error::builder(TestCode::Test1).build();
+        assert_eq!(error.code(), TestCode::Test1);
+        assert_eq!(error.msg(), "");
+        assert!(error.root_cause().is::<Error>());
+
+        let line = line!() + 6;
+        let error = Error::builder(TestCode::Test2)
+            .msg("built")
+            .source(Leaf)
+            .attach_kv("id", 7)
+            .backtrace(BacktracePolicy::Always)
+            .build();
+        assert_eq!(error.msg(), "built");
+        assert!(error.downcast_source_ref::<Leaf>().is_some());
+        assert_eq!(error.get_kv::<i32>("id"), Some(&7));
+        assert_eq!(error.location().unwrap().line(), line);
+        assert!(error.backtrace().is_some());
+
+        let inner = error.code();
+        let error = Error::builder(TestCode::Test1).source(error).backtrace(BacktracePolicy::Never).build();
+        assert!(error.backtrace().is_none());
+        assert_eq!(error.find_code::<TestCode>(), Some(TestCode::Test1));
+        assert_eq!(error.chain().nth(1).unwrap().downcast_ref::<Error>().unwrap().code(), inner);
+
+        let mut error = Error::new(TestCode::Test1, "first").with_source(Leaf);
+        error.set_msg(format!("second {}", 2));
+        assert_eq!(error.msg(), "second 2");
+        assert!(error.root_cause().is::<Leaf>());
+    }
+
+    #[test]
+    fn test_formats() {
+        use super::ResultExt;
+
+        let error = Error::new(TestCode::Test1, "");
+        assert_eq!(error.to_string(), "Test1");
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let error = ret.context(LowerCode::NotFound, "lower")
+            .map_err(|e| e.attach_kv("key", 1))
+            .context(TestCode::Test2, "upper")
+            .unwrap_err();
+        let upper = error.location().unwrap().to_string();
+        let lower = error.find_source::<super::Error<LowerCode>>().unwrap().location().unwrap().to_string();
+
+        assert_eq!(format!("{}", error), "Test2: upper");
+        assert_eq!(format!("{:#}", error), "Test2: upper: NotFound: lower: leaf");
+        assert!(format!("{:?}", error).starts_with(&format!("sfo_result::test::TestCode:Test2, msg:upper\n    at {}\n\n\
+            Caused by:\n    \
+                0: NotFound: lower\n    \
+                1: leaf", upper)));
+
+        let ret: std::result::Result<(), std::io::Error> = Err(std::io::Error::new(std::io::ErrorKind::NotFound, "missing\nfile"));
+        let io_error = ret.context(TestCode::Test1, "open").unwrap_err();
+        let location = io_error.location().unwrap().to_string();
+        assert!(format!("{:?}", io_error).starts_with(&format!("sfo_result::test::TestCode:Test1, msg:open\n    at {}\n\n\
+            Caused by:\n    \
+                missing\n    \
+                file", location)));
+        assert_eq!(format!("{:#?}", error), format!("Error {{
+    code: Test2,
+    msg: \"upper\",
+    location: Some(
+        \"{}\",
+    ),
+    attachments: [],
+    source: Some(
+        Error {{
+            code: NotFound,
+            msg: \"lower\",
+            location: Some(
+                \"{}\",
+            ),
+            attachments: [
+                (
+                    Some(
+                        \"key\",
+                    ),
+                    1,
+                ),
+            ],
+            source: Some(
+                Leaf,
+            ),
+        }},
+    ),
+}}", upper, lower));
+    }
+
+    #[test]
+    fn test_report_handlers() {
+        use super::{AnsiHandler, CompactHandler, ErrorView, LogfmtHandler, PlainHandler, ReportHandler, ResultExt};
+
+        struct With<'a, H>(H, &'a (dyn std::error::Error + 'static));
+
+        impl<H: ReportHandler> std::fmt::Debug for With<'_, H> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.report(ErrorView::of(self.1).unwrap(), f)
+            }
+        }
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let error = ret.context(LowerCode::NotFound, "lower")
+            .map_err(|e| e.attach_kv("key", "a b"))
+            .context(TestCode::Test2, "upper")
+            .unwrap_err();
+        let upper = error.location().unwrap().to_string();
+        let lower = error.find_source::<super::Error<LowerCode>>().unwrap().location().unwrap().to_string();
+
+        let view = ErrorView::of(&error).unwrap();
+        assert_eq!(view.code_type(), "sfo_result::test::TestCode");
+        assert_eq!(view.code(), "Test2");
+        assert_eq!(view.msg(), "upper");
+        assert!(ErrorView::of(&Leaf).is_none());
+
+        assert_eq!(format!("{:?}", With(PlainHandler, &error)), format!("{:?}", error));
+        let colored = format!("{:?}", With(AnsiHandler, &error));
+        assert!(colored.starts_with("\x1b[1;31msfo_result::test::TestCode:Test2\x1b[0m, msg:upper\n    \x1b[2mat "));
+
+        assert_eq!(format!("{:?}", With(CompactHandler, &error)), format!("sfo_result::test::TestCode:Test2, msg:upper at {}; \
+            caused by: sfo_result::test::LowerCode:NotFound, msg:lower at {} [key=\"a b\"]; caused by: leaf", upper, lower));
+
+        assert_eq!(format!("{:?}", With(LogfmtHandler, &error)), format!("code_type=sfo_result::test::TestCode code=Test2 msg=upper \
+            location={} cause=\"NotFound: lower: leaf\"", upper));
+
+        #[cfg(feature = "json")]
+        {
+            use super::JsonHandler;
+
+            let json: serde_json::Value = serde_json::from_str(&format!("{:?}", With(JsonHandler, &error))).unwrap();
+            let mut fields = json.as_object().unwrap().clone();
+            let backtrace = fields.remove("backtrace").unwrap();
+            assert_eq!(serde_json::Value::Object(fields), serde_json::json!({
+                "code": {"type": "sfo_result::test::TestCode", "value": "Test2"},
+                "message": "upper",
+                "location": upper,
+                "attachments": [],
+                "causes": [
+                    {
+                        "type": "sfo_result::Error<sfo_result::test::LowerCode>",
+                        "code": {"type": "sfo_result::test::LowerCode", "value": "NotFound"},
+                        "message": "lower",
+                        "location": lower,
+                        "attachments": [{"key": "key", "value": "\"a b\""}],
+                    },
+                    {
+                        "type": "sfo_result::test::Leaf",
+                        "message": "leaf",
+                    },
+                ],
+                "truncated": null,
+            }));
+            #[cfg(not(feature = "backtrace"))]
+            assert!(backtrace.is_null());
+            #[cfg(feature = "backtrace")]
+            assert!(backtrace.as_array().unwrap()[0]["symbol"].is_string());
+
+            let ret: std::result::Result<(), Leaf> = Err(Leaf);
+            let error = ret.context(TestCode::Test1, "lower").context(LowerCode::NotFound, "upper").unwrap_err();
+            let mut report = error.to_json_report();
+            let code = report["code"].as_object_mut().unwrap();
+            assert_eq!(code.remove("name").unwrap(), "not_found");
+            assert_eq!(code.remove("id").unwrap(), 404);
+            let json: serde_json::Value = serde_json::from_str(&format!("{:?}", With(JsonHandler, &error))).unwrap();
+            assert_eq!(json, report);
+        }
+    }
+
+    #[test]
+    fn test_numbered_causes() {
+        use super::{ErrorView, PlainHandler, ReportHandler, ResultExt};
+
+        #[derive(Debug)]
+        struct Lines;
+
+        impl std::fmt::Display for Lines {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "first line\nsecond line")
+            }
+        }
+
+        impl std::error::Error for Lines {}
+
+        struct Plain<'a>(&'a Error);
+
+        impl std::fmt::Debug for Plain<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                PlainHandler.report(ErrorView::of(self.0).unwrap(), f)
+            }
+        }
+
+        let ret: std::result::Result<(), Lines> = Err(Lines);
+        let error = ret.context(TestCode::Test1, "top").unwrap_err();
+        let report = format!("{:?}", Plain(&error));
+        let (_, causes) = report.split_once("\n\nCaused by:").unwrap();
+        assert!(causes.starts_with("\n    first line\n    second line"));
+
+        let ret: std::result::Result<(), Lines> = Err(Lines);
+        let error = (1..12).fold(ret.context(TestCode::Test1, "0"), |ret, i| ret.context(TestCode::Test1, i.to_string())).unwrap_err();
+        let report = format!("{:?}", Plain(&error));
+        let (_, causes) = report.split_once("\n\nCaused by:").unwrap();
+        assert!(causes.starts_with("\n    0: Test1: 10\n    1: Test1: 9\n    2: Test1: 8\n    3: Test1: 7\n    4: Test1: 6\n    5: Test1: 5\n    \
+            6: Test1: 4\n    7: Test1: 3\n    8: Test1: 2\n    9: Test1: 1\n   10: Test1: 0\n   11: first line\n       second line"));
+        assert!(!causes.contains("\n        second line"));
+
+        struct Shallow<'a>(&'a Error);
+
+        impl std::fmt::Debug for Shallow<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                super::handler::fmt_tree(ErrorView::of(self.0).unwrap(), f, false, super::ChainLimits::new().max_depth(0))
+            }
+        }
+
+        let report = format!("{:?}", Shallow(&error));
+        let (_, causes) = report.split_once("\n\nCaused by:").unwrap();
+        assert!(causes.starts_with("\n    ... (12 more causes)"));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_report() {
+        use super::ResultExt;
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let error = ret.context(TestCode::Test1, "inner")
+            .map_err(|e| e.attach_kv("key", 1))
+            .context(LowerCode::Timeout, "outer")
+            .unwrap_err()
+            .attach("note");
+        let outer = error.location().unwrap().to_string();
+        let inner = error.find_source::<Error>().unwrap().location().unwrap().to_string();
+
+        let mut report = error.to_json_report();
+        let backtrace = report.as_object_mut().unwrap().remove("backtrace").unwrap();
+        assert_eq!(report, serde_json::json!({
+            "code": {"name": "timeout", "id": 504, "type": "sfo_result::test::LowerCode", "value": "Timeout"},
+            "message": "outer",
+            "location": outer,
+            "attachments": [{"key": null, "value": "\"note\""}],
+            "causes": [
+                {
+                    "type": "sfo_result::Error<sfo_result::test::TestCode>",
+                    "code": {"type": "sfo_result::test::TestCode", "value": "Test1"},
+                    "message": "inner",
+                    "location": inner,
+                    "attachments": [{"key": "key", "value": "1"}],
+                },
+                {
+                    "type": "sfo_result::test::Leaf",
+                    "message": "leaf",
+                },
+            ],
+            "truncated": null,
+        }));
+
+        #[cfg(not(feature = "backtrace"))]
+        assert!(backtrace.is_null());
+        #[cfg(feature = "backtrace")]
+        {
+            let frames = backtrace.as_array().unwrap();
+            assert!(!frames.is_empty());
+            assert!(frames.iter().any(|f| f["symbol"].as_str().unwrap().contains("test_json_report") && f["line"].is_u64()));
+        }
+
+        let frames = super::json::frames("   0: app::run\n             at C:\\src\\main.rs:3:18\n      app::inlined\n   1: main\n");
+        assert_eq!(frames, serde_json::json!([
+            {"index": 0, "symbol": "app::run", "file": "C:\\src\\main.rs", "line": 3, "column": 18},
+            {"index": null, "symbol": "app::inlined", "file": null, "line": null, "column": null},
+            {"index": 1, "symbol": "main", "file": null, "line": null, "column": null},
+        ]));
+    }
+
+    #[test]
+    fn test_chain_limits() {
+        use super::{Causes, ChainLimits, ResultExt};
+
+        #[derive(Debug)]
+        struct Cyclic;
+
+        impl std::fmt::Display for Cyclic {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "cyclic")
+            }
+        }
+
+        impl std::error::Error for Cyclic {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(self)
+            }
+        }
+
+        let limits = ChainLimits::new().max_depth(2).max_msg_len(3);
+        assert_eq!(limits.truncate("abcdef").to_string(), "abc...");
+        assert_eq!(limits.truncate("abc").to_string(), "abc");
+        assert_eq!(limits.truncate("中文字符").to_string(), "中文字...");
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let mut error = ret.context(TestCode::Test1, "0").unwrap_err();
+        for i in 1..40 {
+            let ret: std::result::Result<(), Error> = Err(error);
+            error = ret.context(TestCode::Test1, i.to_string()).unwrap_err();
+        }
+        let mut causes = Causes::new(&error, limits);
+        assert_eq!(causes.by_ref().map(|e| e.to_string()).collect::<Vec<_>>(), ["Test1: 38", "Test1: 37"]);
+        let rest = causes.rest();
+        assert_eq!((rest.omitted(), rest.is_cycle()), (38, false));
+        assert_eq!(rest.to_string(), "... (38 more causes)");
+        assert!(Causes::new(&error, ChainLimits::unlimited()).rest().omitted() == 40);
+
+        let display = format!("{:#}", error);
+        assert!(display.starts_with("Test1: 39: Test1: 38: "));
+        assert!(display.ends_with(": Test1: 7: ... (8 more causes)"));
+        let report = format!("{:?}", error);
+        assert_eq!(report.matches("Caused by:").count(), 1);
+        assert!(report.contains("\n    0: Test1: 38\n    1: Test1: 37\n"));
+        assert!(report.contains("\n   31: Test1: 7\n    ... (8 more causes)"));
+
+        let mut causes = Causes::new(&Cyclic, ChainLimits::new());
+        assert!(causes.by_ref().count() <= 1);
+        assert!(causes.rest().is_cycle());
+
+        // A cycle may be found one lap late, see `causes::seen`.
+        let ret: std::result::Result<(), Cyclic> = Err(Cyclic);
+        let error = ret.context(TestCode::Test2, "upper").unwrap_err();
+        let display = format!("{:#}", error);
+        assert!(display.starts_with("Test2: upper: cyclic: "));
+        assert!(display.ends_with(": ... (cycle detected)"));
+        assert!((2..=3).contains(&error.chain().count()));
+        assert!(error.root_cause().is::<Cyclic>());
+        assert!(error.find_source::<Leaf>().is_none());
+        assert!(!error.has_code(TestCode::Test1));
+        let report = error.report();
+        assert!((2..=3).contains(&report.layers().len()));
+        assert!(report.rest().is_cycle());
+        assert!(report.to_string().contains("\n├─▶ cyclic\n╰─▶ ... (cycle detected)"));
+        assert!(format!("{:?}", error).contains("... (cycle detected)"));
+        #[cfg(feature = "json")]
+        {
+            let ret: std::result::Result<(), Cyclic> = Err(Cyclic);
+            let json = ret.context(LowerCode::Timeout, "upper").unwrap_err().to_json_report();
+            assert!((1..=2).contains(&json["causes"].as_array().unwrap().len()));
+            assert_eq!(json["truncated"]["cycle"], true);
+        }
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&error).unwrap();
+            let remote: Error = serde_json::from_str(&json).unwrap();
+            let msgs: Vec<String> = remote.chain().skip(1).map(|e| e.to_string()).collect();
+            assert!(!msgs.is_empty() && msgs.iter().all(|m| m == "cyclic"));
+        }
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let deep = (0..40).fold(ret.context(TestCode::Test1, "0"), |ret, i| ret.context(TestCode::Test1, i.to_string()));
+        let deep = deep.unwrap_err();
+        assert_eq!(deep.chain().count(), 42);
+        assert!(deep.root_cause().is::<Leaf>());
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&deep).unwrap();
+            let remote: Error = serde_json::from_str(&json).unwrap();
+            assert_eq!(remote.chain().count(), 42);
+            assert_eq!(remote.root_cause().to_string(), "leaf");
+        }
+    }
+
+    #[test]
+    fn test_size() {
+        use std::mem::size_of;
+
+        assert_eq!(size_of::<Error>(), size_of::<usize>());
+        assert_eq!(size_of::<super::Error<u64>>(), size_of::<usize>());
+        assert_eq!(size_of::<Option<Error>>(), size_of::<usize>());
+        assert_eq!(size_of::<std::result::Result<(), Error>>(), size_of::<usize>());
+        assert_eq!(size_of::<std::result::Result<u32, Error>>(), 2 * size_of::<usize>());
+    }
+
+    #[test]
+    fn test_result_ext() {
+        use super::ResultExt;
+        use std::error::Error as _;
+
+        let ret: std::result::Result<(), std::fmt::Error> = Err(std::fmt::Error);
+        let error = ret.context(TestCode::Test2, "format failed").unwrap_err();
+        assert_eq!(error.code(), TestCode::Test2);
+        assert_eq!(error.msg(), "format failed");
+        assert!(error.source().unwrap().is::<std::fmt::Error>());
+
+        let ret: super::Result<(), TestCode> = Err(Error::new(TestCode::Test1, "inner".to_string()));
+        let mut called = false;
+        let error = ret.with_context(TestCode::Test2, || {
+            called = true;
+            format!("outer {}", 1)
+        }).unwrap_err();
+        assert!(called);
+        assert_eq!(error.msg(), "outer 1");
+        assert!(error.source().unwrap().is::<Error>());
+
+        let ret: std::result::Result<u32, std::fmt::Error> = Ok(1);
+        let value = ret.with_context(TestCode::Test2, || -> String { unreachable!() }).unwrap();
+        assert_eq!(value, 1);
+
+        let ret: std::result::Result<(), std::fmt::Error> = Err(std::fmt::Error);
+        let error = ret.code(TestCode::Test1).unwrap_err();
+        assert_eq!(error.code(), TestCode::Test1);
+        assert_eq!(error.msg(), "");
+    }
+
+    #[test]
+    fn test_option_ext() {
+        use super::OptionExt;
+        use std::error::Error as _;
+
+        let value = Some(1).ok_or_code(TestCode::Test1, "missing").unwrap();
+        assert_eq!(value, 1);
+
+        let error = None::<u32>.ok_or_code(TestCode::Test2, "missing").unwrap_err();
+        assert_eq!(error.code(), TestCode::Test2);
+        assert_eq!(error.msg(), "missing");
+        assert!(error.source().is_none());
+
+        let key = "user";
+        let error = None::<u32>.ok_or_else_code(TestCode::Test2, || format!("{} not found", key)).unwrap_err();
+        assert_eq!(error.msg(), "user not found");
+    }
+
+    #[test]
+    fn test_bail_ensure() {
+        fn check(value: u32) -> super::Result<u32, TestCode> {
+            ensure!(value > 0, TestCode::Test1, "value must be positive");
+            if value > 10 {
+                bail!(TestCode::Test2, "value {} too large", value);
+            }
+            Ok(value)
+        }
+
+        assert_eq!(check(5).unwrap(), 5);
+
+        let error = check(0).unwrap_err();
+        assert_eq!(error.code(), TestCode::Test1);
+        assert_eq!(error.msg(), "value must be positive");
+
+        let error = check(11).unwrap_err();
+        assert_eq!(error.code(), TestCode::Test2);
+        assert_eq!(error.msg(), "value 11 too large");
+    }
+
+    #[test]
+    fn test_into_err() {
+        use std::error::Error as _;
+
+        let ret: std::result::Result<(), std::fmt::Error> = Err(std::fmt::Error);
+        let error = ret.map_err(into_err!(TestCode::Test2)).unwrap_err();
+        assert_eq!(error.code(), TestCode::Test2);
+        assert_eq!(error.msg(), "");
+        assert!(error.source().unwrap().is::<std::fmt::Error>());
+
+        let ret: std::result::Result<(), std::fmt::Error> = Err(std::fmt::Error);
+        let error = ret.map_err(into_err!(TestCode::Test1, "write {}", "failed")).unwrap_err();
+        assert_eq!(error.code(), TestCode::Test1);
+        assert_eq!(error.msg(), "write failed");
+    }
+
+    #[derive(Debug)]
+    struct Leaf;
+
+    impl std::fmt::Display for Leaf {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "leaf")
+        }
+    }
+
+    impl std::error::Error for Leaf {}
+
+    #[test]
+    fn test_chain() {
+        use super::ResultExt;
+
+        let error = Error::new(TestCode::Test1, "alone".to_string());
+        assert_eq!(error.chain().count(), 1);
+        assert!(error.root_cause().is::<Error>());
+        assert!(error.downcast_source_ref::<Leaf>().is_none());
+        assert!(error.find_source::<Leaf>().is_none());
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let error = ret.context(TestCode::Test1, "inner")
+            .context(TestCode::Test2, "outer")
+            .unwrap_err();
+        let msgs: Vec<String> = error.chain().map(|e| e.to_string()).collect();
+        assert_eq!(msgs.len(), 3);
+        assert_eq!(msgs[2], "leaf");
+        assert!(error.root_cause().is::<Leaf>());
+
+        assert!(error.downcast_source_ref::<Leaf>().is_none());
+        assert_eq!(error.downcast_source_ref::<Error>().unwrap().msg(), "inner");
+        assert!(error.find_source::<Leaf>().is_some());
+        assert_eq!(error.find_source::<Error>().unwrap().msg(), "inner");
+
+        // Shares the address and the `Display` of the error it wraps.
+        #[derive(Debug)]
+        struct Wrapper(std::io::Error);
+
+        impl std::fmt::Display for Wrapper {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                std::fmt::Display::fmt(&self.0, f)
+            }
+        }
+
+        impl std::error::Error for Wrapper {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let ret: std::result::Result<(), Wrapper> = Err(Wrapper(std::io::Error::other("disk")));
+        let error = ret.context(TestCode::Test1, "top").unwrap_err();
+        assert_eq!(format!("{:#}", error), "Test1: top: disk: disk");
+        assert_eq!(error.chain().count(), 3);
+        assert!(error.root_cause().is::<std::io::Error>());
+        assert!(error.find_source::<std::io::Error>().is_some());
+    }
+
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    enum LowerCode {
+        NotFound,
+        Timeout,
+    }
+
+    #[test]
+    fn test_find_code() {
+        use super::ResultExt;
+
+        let ret: super::Result<(), LowerCode> = Err(super::Error::new(LowerCode::NotFound, "lower".to_string()));
+        let error = ret.context(TestCode::Test1, "middle")
+            .context(TestCode::Test2, "upper")
+            .unwrap_err();
+
+        assert_eq!(error.find_code::<LowerCode>(), Some(LowerCode::NotFound));
+        assert_eq!(error.find_code::<TestCode>(), Some(TestCode::Test2));
+        assert_eq!(error.find_code::<u32>(), None);
+
+        assert!(error.has_code(LowerCode::NotFound));
+        assert!(!error.has_code(LowerCode::Timeout));
+        assert!(error.has_code(TestCode::Test1));
+        assert!(error.has_code(TestCode::Test2));
+    }
+
+    impl super::CodeFrom<LowerCode> for TestCode {
+        fn code_from(code: LowerCode) -> Self {
+            match code {
+                LowerCode::NotFound => TestCode::Test1,
+                LowerCode::Timeout => TestCode::Test2,
+            }
+        }
+    }
+
+    #[test]
+    fn test_map_code() {
+        use super::CodeResultExt;
+        use std::error::Error as _;
+
+        let error = super::Error::<LowerCode>::from((LowerCode::Timeout, "lower", Leaf));
+        #[cfg(feature = "backtrace")]
+        let backtrace = error.backtrace().unwrap().to_string();
+        let error = error.map_code(|c| c == LowerCode::Timeout);
+        #[cfg(feature = "backtrace")]
+        assert_eq!(error.backtrace().unwrap().to_string(), backtrace);
+        assert!(error.code());
+        assert_eq!(error.msg(), "lower");
+        assert!(error.source().unwrap().is::<Leaf>());
+
+        fn lower() -> super::Result<(), LowerCode> {
+            Err(super::Error::new(LowerCode::NotFound, "not found".to_string()))
+        }
+
+        fn upper() -> super::Result<(), TestCode> {
+            lower().into_code()?;
+            Ok(())
+        }
+
+        let error = upper().unwrap_err();
+        assert_eq!(error.code(), TestCode::Test1);
+        assert_eq!(error.msg(), "not found");
+        assert!(error.source().is_none());
+    }
+
+    impl super::ErrorCode for LowerCode {
+        fn id(&self) -> u32 {
+            match self {
+                LowerCode::NotFound => 404,
+                LowerCode::Timeout => 504,
+            }
+        }
+
+        fn name(&self) -> &'static str {
+            match self {
+                LowerCode::NotFound => "not_found",
+                LowerCode::Timeout => "timeout",
+            }
+        }
+
+        fn category(&self) -> super::ErrorCategory {
+            match self {
+                LowerCode::NotFound => super::ErrorCategory::User,
+                LowerCode::Timeout => super::ErrorCategory::Transient,
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_code() {
+        use super::{ErrorCategory, Severity};
+
+        let error = super::Error::new(LowerCode::Timeout, "slow".to_string());
+        assert_eq!(error.code_id(), 504);
+        assert_eq!(error.code_name(), "timeout");
+        assert_eq!(error.category(), ErrorCategory::Transient);
+        assert_eq!(error.severity(), Severity::Error);
+
+        let error = super::Error::new(LowerCode::NotFound, "missing".to_string());
+        assert_eq!(error.code_id(), 404);
+        assert_eq!(error.category(), ErrorCategory::User);
+    }
+
+    #[derive(Copy, Clone, Debug, Eq, PartialEq, sfo_result_derive::ErrorCode)]
+    enum DerivedCode {
+        #[code(id = 1001, msg = "not found", category = "user")]
+        NotFound,
+        #[code(id = 1002, name = "busy", retryable, severity = "warning")]
+        Busy,
+        #[code(id = 1003, category = "transient")]
+        Unavailable,
+    }
+
+    #[derive(Copy, Clone, Debug, Eq, PartialEq, sfo_result_derive::ErrorCode)]
+    #[code(crate = "crate")]
+    enum RenamedCode {
+        #[code(id = 1, category = "transient")]
+        Retry,
+    }
+
+    #[test]
+    fn test_derive_error_code() {
+        use super::{ErrorCategory, ErrorCode, Severity};
+
+        assert_eq!(DerivedCode::NotFound.id(), 1001);
+        assert_eq!(DerivedCode::NotFound.name(), "NotFound");
+        assert_eq!(DerivedCode::NotFound.message(), "not found");
+        assert_eq!(DerivedCode::NotFound.category(), ErrorCategory::User);
+        assert!(!DerivedCode::NotFound.is_retryable());
+        assert_eq!(DerivedCode::NotFound.to_string(), "NotFound");
+
+        assert_eq!(DerivedCode::Busy.name(), "busy");
+        assert_eq!(DerivedCode::Busy.message(), "");
+        assert_eq!(DerivedCode::Busy.category(), ErrorCategory::Internal);
+        assert_eq!(DerivedCode::Busy.severity(), Severity::Warning);
+        assert!(DerivedCode::Busy.is_retryable());
+
+        assert_eq!(DerivedCode::Unavailable.severity(), Severity::Error);
+        assert!(DerivedCode::Unavailable.is_retryable());
+
+        let error = super::Error::from_code(DerivedCode::NotFound);
+        assert_eq!(error.code_id(), 1001);
+        assert!(matches!(error.inner.msg, std::borrow::Cow::Borrowed("not found")));
+
+        assert_eq!(RenamedCode::Retry.id(), 1);
+        assert!(RenamedCode::Retry.is_retryable());
+    }
+
+    #[test]
+    fn test_attachments() {
+        #[derive(Debug, PartialEq)]
+        struct RequestId(u64);
+
+        let error = Error::new(TestCode::Test1, "request failed".to_string())
+            .attach(RequestId(7))
+            .attach_kv("user_id", 42u32)
+            .attach_kv("path", "/tmp/a".to_string())
+            .attach_kv("retry_after", std::time::Duration::from_secs(3));
+
+        assert_eq!(error.get_attachment::<RequestId>(), Some(&RequestId(7)));
+        assert_eq!(error.get_attachment::<u32>(), Some(&42));
+        assert_eq!(error.get_attachment::<i64>(), None);
+        assert_eq!(error.get_kv::<u32>("user_id"), Some(&42));
+        assert_eq!(error.get_kv::<String>("path").map(|p| p.as_str()), Some("/tmp/a"));
+        assert_eq!(error.get_kv::<std::time::Duration>("retry_after"), Some(&std::time::Duration::from_secs(3)));
+        assert_eq!(error.get_kv::<u64>("user_id"), None);
+        assert_eq!(error.attachments().count(), 4);
+
+        let report = format!("{:?}", error);
+        assert!(report.contains("\nAttachment: RequestId(7)"));
+        assert!(report.contains("\nAttachment: user_id=42"));
+
+        let error = error.map_code(|_| 1u8);
+        assert_eq!(error.get_kv::<u32>("user_id"), Some(&42));
+    }
+
+    #[test]
+    fn test_location() {
+        use super::{OptionExt, ResultExt};
+
+        fn at(line: u32) -> String {
+            format!("{}:{}", file!(), line)
+        }
+        fn location(error: &Error) -> String {
+            let location = error.location().unwrap();
+            format!("{}:{}", location.file(), location.line())
+        }
+
+        let error = Error::new(TestCode::Test1, "new".to_string());
+        assert_eq!(location(&error), at(line!() - 1));
+
+        let error = err!(TestCode::Test1, "err");
+        assert_eq!(location(&error), at(line!() - 1));
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let error = ret.context(TestCode::Test1, "context").unwrap_err();
+        assert_eq!(location(&error), at(line!() - 1));
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let error = ret.map_err(into_err!(TestCode::Test1, "into_err")).unwrap_err();
+        assert_eq!(location(&error), at(line!() - 1));
+
+        let error = None::<u32>.ok_or_else_code(TestCode::Test1, || "none").unwrap_err();
+        assert_eq!(location(&error), at(line!() - 1));
+
+        fn check() -> super::Result<(), TestCode> {
+            bail!(TestCode::Test2, "bail");
+        }
+        let error = check().unwrap_err();
+        assert_eq!(location(&error), at(line!() - 3));
+
+        let report = format!("{:?}", error);
+        assert!(report.starts_with(&format!("sfo_result::test::TestCode:Test2, msg:bail\n    at {}:", at(line!() - 6))));
+    }
+
+    #[test]
+    fn test_backtrace_policy() {
+        use super::BacktracePolicy;
+        use std::backtrace::BacktraceStatus;
+
+        fn captured(policy: &BacktracePolicy, code: &dyn std::any::Any) -> bool {
+            policy.capture(code).is_some_and(|b| b.status() == BacktraceStatus::Captured)
+        }
+
+        assert!(!captured(&BacktracePolicy::Never, &TestCode::Test1));
+        assert!(captured(&BacktracePolicy::Always, &TestCode::Test1));
+        assert!(BacktracePolicy::Env.capture(&TestCode::Test1).is_some());
+        assert!(!captured(&BacktracePolicy::Sample(0), &TestCode::Test1));
+
+        let sample = BacktracePolicy::Sample(3);
+        let count = (0..30).filter(|_| captured(&sample, &TestCode::Test1)).count();
+        assert!((9..=11).contains(&count));
+
+        let codes = BacktracePolicy::codes([TestCode::Test2, TestCode::Test1]);
+        assert!(captured(&codes, &TestCode::Test2));
+        let code = BacktracePolicy::code(LowerCode::Timeout);
+        assert!(captured(&code, &LowerCode::Timeout));
+        assert!(!captured(&code, &LowerCode::NotFound));
+        assert!(!captured(&code, &TestCode::Test1));
+
+        #[cfg(feature = "backtrace")]
+        assert!(matches!(super::backtrace_policy(), BacktracePolicy::Always));
+        #[cfg(not(feature = "backtrace"))]
+        assert!(matches!(super::backtrace_policy(), BacktracePolicy::Never));
+    }
+
+    #[test]
+    fn test_backtrace_format() {
+        use super::BacktraceFormat;
+
+        let backtrace = "   0: app::read\n             at ./src/io.rs:10:5\n   1: serde_json::de::from_str\n             at /cargo/serde_json/src/de.rs:20:9\n   2: tokio::runtime::block_on\n   3: app::main\n             at ./src/main.rs:3:18\n   4: core::ops::function::FnOnce::call_once\n             at /rustc/library/core/src/ops/function.rs:250:5\n   5: std::rt::lang_start::{{closure}}\n   6: <&dyn core::ops::function::Fn<()> as core::ops::function::FnOnce<()>>::call_once\n   7: main\n   8: __libc_start_main\n   9: _start\n";
+
+        assert_eq!(BacktraceFormat::full().render(backtrace), backtrace);
+        assert_eq!(BacktraceFormat::default().hide_std(true).render(backtrace), "   0: app::read\n             at ./src/io.rs:10:5\n   1: serde_json::de::from_str\n             at /cargo/serde_json/src/de.rs:20:9\n   2: tokio::runtime::block_on\n   3: app::main\n             at ./src/main.rs:3:18\n");
+        assert_eq!(BacktraceFormat::default().crates(["app"]).render(backtrace), "   0: app::read\n             at ./src/io.rs:10:5\n      ... 2 frames omitted\n   3: app::main\n             at ./src/main.rs:3:18\n      ... 6 frames omitted\n");
+        assert_eq!(BacktraceFormat::compact().crates(["app", "tokio"]).render(backtrace), "   0: ./src/io.rs:10\n      ... 1 frame omitted\n   2: tokio::runtime::block_on\n   3: ./src/main.rs:3\n");
+
+        let backtrace = "   0: apple::read\n   1: app\n   2: <app::Reader as std::io::Read>::read\n   3: app_util::main\n";
+        assert_eq!(BacktraceFormat::default().crates(["app"]).render(backtrace), "      ... 1 frame omitted\n   1: app\n   2: <app::Reader as std::io::Read>::read\n      ... 1 frame omitted\n");
+    }
+
+    #[test]
+    fn test_backtrace_dedup() {
+        use super::BacktracePolicy;
+
+        #[derive(Debug)]
+        struct Wrapper(Error);
+
+        impl std::fmt::Display for Wrapper {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "wrapper")
+            }
+        }
+
+        impl std::error::Error for Wrapper {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let inner = Error::builder(TestCode::Test1).backtrace(BacktracePolicy::Always).build();
+        assert!(inner.backtrace().is_some());
+
+        let outer = Error::builder(TestCode::Test2).source(inner).backtrace(BacktracePolicy::Always).build();
+        assert!(outer.backtrace().is_none());
+        assert_eq!(format!("{:?}", outer).matches("Stack backtrace:").count(), 1);
+
+        // Errors that are not sfo errors in between are looked through.
+        let outer = Error::builder(TestCode::Test1).source(Wrapper(outer)).backtrace(BacktracePolicy::Always).build();
+        assert!(outer.backtrace().is_none());
+        let outer = outer.map_code(|_| LowerCode::NotFound);
+        let outer = super::Error::builder(TestCode::Test2).source(outer).backtrace(BacktracePolicy::Always).build();
+        assert!(outer.backtrace().is_none());
+
+        let plain = Error::builder(TestCode::Test1).backtrace(BacktracePolicy::Never).build();
+        let outer = Error::builder(TestCode::Test2).source(plain).backtrace(BacktracePolicy::Always).build();
+        assert!(outer.backtrace().is_some());
+
+        let inner = Error::builder(TestCode::Test1).backtrace(BacktracePolicy::Always).build();
+        let outer = Error::builder(TestCode::Test2).backtrace(BacktracePolicy::Never).build().with_source(inner);
+        let outer = Error::builder(TestCode::Test1).source(outer).backtrace(BacktracePolicy::Always).build();
+        assert!(outer.backtrace().is_none());
+    }
+
+    #[test]
+    fn test_report() {
+        use super::ResultExt;
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let error = ret.context(LowerCode::NotFound, "lower").map_err(|e| e.attach_kv("key", 1));
+        let lower = error.as_ref().unwrap_err().location().unwrap().to_string();
+        let error = error.context(TestCode::Test2, "upper").unwrap_err();
+        let upper = error.location().unwrap().to_string();
+
+        let report = error.report();
+        assert_eq!(report.layers().len(), 3);
+        assert_eq!(report.layers()[0].code(), Some("sfo_result::test::TestCode:Test2"));
+        assert_eq!(report.layers()[1].msg(), "lower");
+        assert_eq!(report.layers()[1].location(), Some(lower.as_str()));
+        assert_eq!(report.layers()[2].code(), None);
+        assert_eq!(report.layers()[2].location(), None);
+
+        let rendered = report.to_string();
+        assert!(rendered.starts_with(&format!("sfo_result::test::TestCode:Test2, msg:upper, at {}\n\
+            ├─▶ sfo_result::test::LowerCode:NotFound, msg:lower, at {}\n\
+            ╰─▶ leaf", upper, lower)));
+
+        #[cfg(not(feature = "backtrace"))]
+        assert!(report.backtrace().is_none());
+        #[cfg(feature = "backtrace")]
+        {
+            assert_eq!(rendered.matches("Stack backtrace:").count(), 1);
+            assert_eq!(format!("{:?}", error).matches("Stack backtrace:").count(), 1);
+            assert!(error.backtrace().is_none());
+            let lower = error.find_source::<super::Error<LowerCode>>().unwrap();
+            assert!(lower.backtrace().is_some());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_chain() {
+        use super::{RemoteError, ResultExt};
+
+        let ret: std::result::Result<(), Leaf> = Err(Leaf);
+        let error = ret.context(LowerCode::NotFound, "lower")
+            .context(TestCode::Test2, "upper")
+            .unwrap_err();
+        let location = error.location().unwrap().to_string();
+
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["code"], "Test2");
+        assert_eq!(json["msg"], "upper");
+        assert_eq!(json["location"], location.as_str());
+        assert_eq!(json["causes"], serde_json::json!([
+            {
+                "type": "sfo_result::Error<sfo_result::test::LowerCode>",
+                "message": "NotFound: lower",
+            },
+            {
+                "type": "sfo_result::test::Leaf",
+                "message": "leaf",
+            },
+        ]));
+        #[cfg(not(feature = "backtrace"))]
+        assert!(json["backtrace"].is_null());
+        #[cfg(feature = "backtrace")]
+        assert!(json["backtrace"].is_string());
+
+        let remote: Error = serde_json::from_value(json).unwrap();
+        let peer: Error = serde_json::from_str(r#"{"code":"Test1","msg":"old peer"}"#).unwrap();
+        assert_eq!(peer.msg(), "old peer");
+        assert_eq!(remote.code(), TestCode::Test2);
+        assert_eq!(remote.msg(), "upper");
+        assert_eq!(remote.chain().count(), 3);
+        let root = remote.root_cause().downcast_ref::<RemoteError>().unwrap();
+        assert_eq!(root.type_name(), Some("sfo_result::test::Leaf"));
+        assert_eq!(root.message(), "leaf");
+        let lower = remote.chain().nth(1).unwrap().downcast_ref::<RemoteError>().unwrap();
+        assert_eq!(format!("{:?}", lower), "sfo_result::Error<sfo_result::test::LowerCode>: NotFound: lower");
+        assert!(!format!("{:#?}", remote).contains("Caused by"));
+
+        assert!(remote.location().is_none());
+
+        let report = format!("{:?}", remote);
+        assert!(report.starts_with(&format!("sfo_result::test::TestCode:Test2, msg:upper\n    at {}\n\n\
+            Caused by:\n    \
+                0: NotFound: lower\n    \
+                1: leaf", location)));
+        assert_eq!(report, format!("{:?}", error));
+        #[cfg(feature = "backtrace")]
+        assert!(report.contains("Stack backtrace:"));
+    }
+
+    #[cfg(feature = "log")]
+    mod log_capture {
+        use std::sync::Mutex;
+
+        static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        struct CaptureLogger;
+
+        impl log::Log for CaptureLogger {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                RECORDS.lock().unwrap().push(format!("{} {}", record.level(), record.args()));
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CaptureLogger = CaptureLogger;
+
+        pub fn logged(msg: &str) -> bool {
+            RECORDS.lock().unwrap().iter().any(|r| r == msg)
+        }
+
+        pub fn init() {
+            let _ = log::set_logger(&LOGGER);
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn test_macro_log() {
+        log_capture::init();
+
+        let _ = err!(TestCode::Test1, "log feature {}", "on");
+        assert!(log_capture::logged("ERROR log feature on"));
+
+        let ret: std::result::Result<(), std::fmt::Error> = Err(std::fmt::Error);
+        let _ = ret.map_err(into_err!(TestCode::Test1, "into_err log")).unwrap_err();
+        assert!(log_capture::logged("ERROR into_err log err:Error"));
+    }
+
+    #[cfg(not(feature = "log"))]
+    #[test]
+    fn test_macro_without_log() {
+        let error = err!(TestCode::Test1, "log feature {}", "off");
+        assert_eq!(error.msg(), "log feature off");
+
+        let ret: std::result::Result<(), std::fmt::Error> = Err(std::fmt::Error);
+        let error = ret.map_err(into_err!(TestCode::Test2, "into_err")).unwrap_err();
+        assert_eq!(error.msg(), "into_err");
+    }
 }
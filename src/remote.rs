@@ -0,0 +1,150 @@
+use std::fmt::{Debug, Display, Formatter};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::erased::erase;
+use crate::{Error, ErrorBacktrace, ErrorImpl, ErrorLocation};
+
+/// Opaque stand-in for a cause that was serialized on another process. Only
+/// the type name and message of the original error survive the trip.
+#[derive(Clone)]
+pub struct RemoteError {
+    type_name: Option<String>,
+    message: String,
+    source: Option<Box<RemoteError>>,
+}
+
+impl RemoteError {
+    pub fn type_name(&self) -> Option<&str> {
+        self.type_name.as_deref()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl std::error::Error for RemoteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|e| e.as_ref() as _)
+    }
+}
+
+/// Only this cause, its sources are listed by the report of the error above.
+impl Debug for RemoteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(type_name) = &self.type_name {
+            write!(f, "{}: ", type_name)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Cause {
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub type_name: Option<String>,
+    pub message: String,
+}
+
+impl<T: Debug + Copy + Send + Sync + 'static> Error<T> {
+    // Flattens the source chain into the messages the local report shows. The
+    // type of a cause is only known when the error that wraps it is an sfo
+    // error, which records it when wrapping. The whole chain is sent, only a
+    // cycle ends it early.
+    pub(crate) fn causes(&self) -> Vec<Cause> {
+        let mut causes = Vec::new();
+        let mut type_name = self.inner.source_type.map(|t| t.to_string());
+        for e in self.chain().skip(1) {
+            if let Some(remote) = e.downcast_ref::<RemoteError>() {
+                causes.push(Cause {
+                    type_name: remote.type_name.clone(),
+                    message: remote.message.clone(),
+                });
+                type_name = None;
+            } else if let Some(erased) = erase(e) {
+                causes.push(Cause {
+                    type_name: Some(erased.type_name().to_string()),
+                    message: e.to_string(),
+                });
+                type_name = erased.source_type().map(|t| t.to_string());
+            } else {
+                causes.push(Cause {
+                    type_name: type_name.take(),
+                    message: e.to_string(),
+                });
+            }
+        }
+        causes
+    }
+}
+
+fn remote_chain(causes: Vec<Cause>) -> Option<Box<RemoteError>> {
+    causes.into_iter().rev().fold(None, |source, cause| {
+        Some(Box::new(RemoteError {
+            type_name: cause.type_name,
+            message: cause.message,
+            source,
+        }))
+    })
+}
+
+#[derive(Serialize)]
+struct ErrorRef<'a, T> {
+    code: &'a T,
+    msg: &'a str,
+    location: Option<String>,
+    causes: Vec<Cause>,
+    backtrace: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorOwned<T> {
+    code: T,
+    msg: String,
+    #[serde(default)]
+    location: Option<String>,
+    #[serde(default)]
+    causes: Vec<Cause>,
+    #[serde(default)]
+    backtrace: Option<String>,
+}
+
+impl<T: Serialize + Debug + Copy + Send + Sync + 'static> Serialize for Error<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ErrorRef {
+            code: &self.inner.code,
+            msg: &self.inner.msg,
+            location: self.inner.location.as_ref().map(|l| l.to_string()),
+            causes: self.causes(),
+            // Wrapping errors skip capture when a cause already has a
+            // backtrace, and causes are flattened to messages here.
+            backtrace: self.chain().filter_map(erase).filter_map(|e| e.backtrace_string()).last(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de> + Debug + Copy + Send + Sync + 'static> Deserialize<'de> for Error<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let owned = ErrorOwned::<T>::deserialize(deserializer)?;
+        crate::erased::register::<T>();
+        Ok(Error {
+            inner: Box::new(ErrorImpl {
+                code: owned.code,
+                msg: owned.msg.into(),
+                source: remote_chain(owned.causes).map(|e| e as _),
+                source_type: None,
+                chain_backtrace: owned.backtrace.is_some(),
+                backtrace: owned.backtrace.map(ErrorBacktrace::Remote),
+                location: owned.location.map(ErrorLocation::Remote),
+                attachments: Vec::new(),
+            }),
+        })
+    }
+}
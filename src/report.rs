@@ -0,0 +1,127 @@
+use std::fmt::{Debug, Display, Formatter};
+
+use crate::erased::{erase, Code, ErasedError};
+use crate::{chain_limits, fmt_backtrace, Causes, Rest};
+
+/// One wrapping layer of an error. Layers that are not sfo errors only carry
+/// the message of their `Display` impl.
+pub struct Layer {
+    code: Option<String>,
+    msg: String,
+    location: Option<String>,
+}
+
+impl Layer {
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    pub fn msg(&self) -> &str {
+        &self.msg
+    }
+
+    pub fn location(&self) -> Option<&str> {
+        self.location.as_deref()
+    }
+}
+
+impl Display for Layer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.code {
+            Some(code) => {
+                write!(f, "{}", code)?;
+                if !self.msg.is_empty() {
+                    write!(f, ", msg:{}", self.msg)?;
+                }
+            }
+            None => write!(f, "{}", self.msg)?,
+        }
+        if let Some(location) = &self.location {
+            write!(f, ", at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+/// The whole source chain of an error rendered as one tree, with a single
+/// backtrace taken from the innermost layer that captured one. The chain is
+/// bounded by `chain_limits`, `rest` tells what was left out.
+pub struct Report {
+    layers: Vec<Layer>,
+    rest: Rest,
+    backtrace: Option<String>,
+}
+
+impl Report {
+    pub(crate) fn new(error: &dyn ErasedError) -> Self {
+        let mut layers = vec![Self::layer(error)];
+        let mut backtrace = error.backtrace_string();
+        let mut causes = Causes::new(error.as_error(), chain_limits());
+        for e in causes.by_ref() {
+            match erase(e) {
+                Some(erased) => {
+                    layers.push(Self::layer(erased));
+                    if let Some(inner) = erased.backtrace_string() {
+                        backtrace = Some(inner);
+                    }
+                }
+                None => layers.push(Layer {
+                    code: None,
+                    msg: e.to_string(),
+                    location: None,
+                }),
+            }
+        }
+        Self {
+            layers,
+            rest: causes.rest(),
+            backtrace,
+        }
+    }
+
+    fn layer(error: &dyn ErasedError) -> Layer {
+        Layer {
+            code: Some(Code(error).to_string()),
+            msg: error.msg().to_string(),
+            location: error.location().map(|l| l.to_string()),
+        }
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    pub fn rest(&self) -> Rest {
+        self.rest
+    }
+
+    pub fn backtrace(&self) -> Option<&str> {
+        self.backtrace.as_deref()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let last = self.layers.len() - usize::from(self.rest.is_empty());
+        for (i, layer) in self.layers.iter().enumerate() {
+            match i {
+                0 => write!(f, "{}", layer)?,
+                i if i == last => write!(f, "\n╰─▶ {}", layer)?,
+                _ => write!(f, "\n├─▶ {}", layer)?,
+            }
+        }
+        if !self.rest.is_empty() {
+            write!(f, "\n╰─▶ {}", self.rest)?;
+        }
+        if let Some(backtrace) = &self.backtrace {
+            fmt_backtrace(f, backtrace.clone())?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
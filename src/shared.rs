@@ -0,0 +1,73 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::Error;
+
+/// A reference counted `Error<T>` for broadcasting one failure to many
+/// receivers. It derefs to the wrapped error and reports the same source
+/// chain, so it can stand in for it anywhere an error is expected.
+pub struct SharedError<T>(Arc<Error<T>>);
+
+impl<T> SharedError<T> {
+    pub fn new(error: Error<T>) -> Self {
+        Self(Arc::new(error))
+    }
+
+    pub fn error(&self) -> &Error<T> {
+        &self.0
+    }
+
+    /// The wrapped error, if this is the last reference to it.
+    pub fn try_unwrap(self) -> Result<Error<T>, Self> {
+        Arc::try_unwrap(self.0).map_err(Self)
+    }
+
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<T> Clone for SharedError<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Deref for SharedError<T> {
+    type Target = Error<T>;
+
+    fn deref(&self) -> &Error<T> {
+        &self.0
+    }
+}
+
+impl<T> From<Error<T>> for SharedError<T> {
+    fn from(error: Error<T>) -> Self {
+        Self::new(error)
+    }
+}
+
+impl<T> From<Arc<Error<T>>> for SharedError<T> {
+    fn from(error: Arc<Error<T>>) -> Self {
+        Self(error)
+    }
+}
+
+impl<T: Debug + Copy + 'static> std::error::Error for SharedError<T> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+impl<T: Debug + Copy + 'static> Debug for SharedError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&*self.0, f)
+    }
+}
+
+impl<T: Debug + Copy + 'static> Display for SharedError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&*self.0, f)
+    }
+}